@@ -0,0 +1,187 @@
+//! Keeps the backup password in memory for `--password-storage prompt`, so it
+//! only has to be typed once per session.
+
+use std::{env, path::PathBuf};
+
+use color_eyre::Result;
+
+#[cfg(unix)]
+pub use self::unix::{get, serve, set};
+#[cfg(not(unix))]
+pub use self::unsupported::{get, serve, set};
+
+/// `MAMBEMBE_AGENT_SOCKET` or a socket in mambembe's private runtime
+/// directory.
+fn socket_path() -> Result<PathBuf> {
+    match env::var_os("MAMBEMBE_AGENT_SOCKET") {
+        Some(path) => Ok(PathBuf::from(path)),
+        None => Ok(mambembe_keyring::runtime_dir()?.join("agent.sock")),
+    }
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::{
+        fs,
+        io::{self, BufRead, BufReader, Write},
+        os::unix::{
+            fs::{FileTypeExt, MetadataExt, PermissionsExt},
+            net::UnixStream,
+        },
+        path::Path,
+        sync::Arc,
+    };
+
+    use color_eyre::{
+        eyre::{eyre, Context},
+        Result,
+    };
+    use mambembe_keyring::current_uid;
+    use mambembe_lib::secret::Secret;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt},
+        net::{UnixListener, UnixStream as AsyncUnixStream},
+        signal,
+        sync::Mutex,
+    };
+    use tracing::{info, warn};
+
+    use super::socket_path;
+
+    type Password = Arc<Mutex<Option<Secret<String>>>>;
+
+    /// Serves the password until interrupted, it is never written anywhere.
+    pub async fn serve() -> Result<()> {
+        let path = socket_path()?;
+        if fs::symlink_metadata(&path).is_ok() {
+            if !is_own_socket(&path) {
+                return Err(eyre!(
+                    "{:?} is not a socket of the current user, refusing to replace it",
+                    path
+                ));
+            }
+            if UnixStream::connect(&path).is_ok() {
+                return Err(eyre!("an agent is already listening on {:?}", path));
+            }
+            // Left behind by an agent that did not exit cleanly
+            fs::remove_file(&path).wrap_err("failed to remove the old agent socket")?;
+        }
+        let listener = UnixListener::bind(&path).wrap_err("failed to bind the agent socket")?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600))
+            .wrap_err("failed to restrict the agent socket")?;
+        println!("Agent listening on {:?}", path);
+
+        let password = Password::default();
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let (stream, _) = accepted.wrap_err("failed to accept agent connection")?;
+                    match stream.peer_cred() {
+                        Ok(cred) if cred.uid() == current_uid() => {}
+                        _ => {
+                            warn!("Refusing agent connection from another user");
+                            continue;
+                        }
+                    }
+                    let password = password.clone();
+                    tokio::spawn(async move {
+                        if let Err(err) = handle(stream, password).await {
+                            warn!("Agent connection failed: {}", err);
+                        }
+                    });
+                }
+                _ = signal::ctrl_c() => break,
+            }
+        }
+        info!("Stopping agent");
+        fs::remove_file(&path).wrap_err("failed to remove the agent socket")
+    }
+
+    /// One command per connection, `get` or `set <password>`.
+    async fn handle(mut stream: AsyncUnixStream, password: Password) -> io::Result<()> {
+        let (reader, mut writer) = stream.split();
+        let mut line = Secret::<String>::default();
+        tokio::io::BufReader::new(reader)
+            .read_line(line.expose_mut())
+            .await?;
+        let line = line.expose().trim_end_matches('\n');
+        if line == "get" {
+            if let Some(current) = password.lock().await.as_ref() {
+                writer.write_all(current.expose().as_bytes()).await?;
+            }
+            writer.write_all(b"\n").await?;
+        } else if let Some(new) = line.strip_prefix("set ") {
+            *password.lock().await = Some(Secret::new(new.to_string()));
+        } else if !line.is_empty() {
+            warn!("Unknown agent command");
+        }
+        Ok(())
+    }
+
+    /// Only the current user's sockets are trusted, so the password is never
+    /// sent to or read from an agent run by someone else.
+    fn is_own_socket(path: &Path) -> bool {
+        fs::symlink_metadata(path).is_ok_and(|metadata| {
+            metadata.file_type().is_socket() && metadata.uid() == current_uid()
+        })
+    }
+
+    fn connect() -> Option<UnixStream> {
+        let path = match socket_path() {
+            Ok(path) => path,
+            Err(err) => {
+                warn!("Failed to find the agent socket: {}", err);
+                return None;
+            }
+        };
+        if fs::symlink_metadata(&path).is_err() {
+            return None;
+        }
+        if !is_own_socket(&path) {
+            warn!(
+                "Ignoring {:?}, it is not a socket of the current user",
+                path
+            );
+            return None;
+        }
+        UnixStream::connect(path).ok()
+    }
+
+    /// None when no agent is running or it does not know the password yet.
+    pub fn get() -> Option<Secret<String>> {
+        let mut stream = connect()?;
+        stream.write_all(b"get\n").ok()?;
+        let mut password = Secret::<String>::default();
+        BufReader::new(stream)
+            .read_line(password.expose_mut())
+            .ok()?;
+        let trimmed = password.expose().trim_end_matches('\n').len();
+        password.expose_mut().truncate(trimmed);
+        (!password.expose().is_empty()).then_some(password)
+    }
+
+    /// Does nothing when no agent is running.
+    pub fn set(password: &str) {
+        if let Some(mut stream) = connect() {
+            if let Err(err) = writeln!(stream, "set {}", password) {
+                warn!("Failed to send the password to the agent: {}", err);
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod unsupported {
+    use color_eyre::{eyre::eyre, Result};
+    use mambembe_lib::secret::Secret;
+
+    pub async fn serve() -> Result<()> {
+        Err(eyre!("the agent is only available on unix"))
+    }
+
+    pub fn get() -> Option<Secret<String>> {
+        None
+    }
+
+    pub fn set(_password: &str) {}
+}
@@ -1,29 +1,94 @@
+mod agent;
 mod output;
 
-use std::{process::exit, time::Duration};
+use std::{collections::BTreeMap, path::PathBuf, process::exit, time::Duration};
 
-use color_eyre::{config::HookBuilder, eyre::Context, Result};
+use color_eyre::{
+    config::HookBuilder,
+    eyre::{eyre, Context},
+    Result,
+};
 use fuzzy_matcher::{skim::SkimMatcherV2, FuzzyMatcher};
-use mambembe_keyring::MambembeKeyringError;
+use mambembe_keyring::{BackupPassword, CacheDiff, Cached, Data, MambembeKeyringError};
 use mambembe_lib::{
     client::AuthyClientApi,
     models::{
-        AuthenticatorToken, CheckRegistrationStatus, CheckStatusResponse, RegisterDeviceResponse,
+        AuthenticatorApp, AuthenticatorToken, CheckRegistrationStatus, CheckStatusResponse,
+        HotpToken, OtpAlgorithm, PasswordStorage, RegisterDeviceResponse, TokenSettings,
+        VerificationMethod,
     },
-    AuthyClient,
+    secret::Secret,
+    AuthyClient, AuthyClientBuilder, MambembeError,
 };
+use serde::de::DeserializeOwned;
 use structopt::StructOpt;
 use tokio::{
     io,
-    io::{AsyncBufReadExt, BufReader},
+    io::{AsyncBufReadExt, BufReader, Lines, Stdin},
     time::sleep,
 };
-use tracing::{info, instrument};
+use tracing::{info, instrument, warn};
 use tracing_error::ErrorLayer;
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
 use crate::output::{Output, ServiceToken};
 
+type Input = Lines<BufReader<Stdin>>;
+
+#[derive(Debug, StructOpt)]
+struct Opts {
+    #[structopt(flatten)]
+    connection: ConnectionArgs,
+    #[structopt(subcommand)]
+    command: Config,
+}
+
+#[derive(Debug, StructOpt)]
+struct ConnectionArgs {
+    #[structopt(
+        long,
+        env = "MAMBEMBE_PROXY",
+        help = "http, https or socks5 proxy used to reach authy"
+    )]
+    proxy: Option<String>,
+    #[structopt(
+        long,
+        env = "MAMBEMBE_CA_BUNDLE",
+        help = "pem file with extra certificates to trust"
+    )]
+    ca_bundle: Option<PathBuf>,
+    #[structopt(long, env = "MAMBEMBE_USER_AGENT", help = "user agent sent to authy")]
+    user_agent: Option<String>,
+    #[structopt(long, env = "MAMBEMBE_API_URL", help = "authy api base url")]
+    api_url: Option<String>,
+    #[structopt(
+        long,
+        help = "never contact authy, tokens are generated from the keyring cache only"
+    )]
+    offline: bool,
+}
+
+impl ConnectionArgs {
+    fn client_builder(&self) -> Result<AuthyClientBuilder> {
+        let mut builder = AuthyClientBuilder::new().offline(self.offline);
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(proxy);
+        }
+        if let Some(ca_bundle) = &self.ca_bundle {
+            let pem = std::fs::read(ca_bundle)
+                .wrap_err_with(|| format!("failed to read ca bundle {:?}", ca_bundle))?;
+            builder = builder.root_certificates_pem(&pem);
+        }
+        if let Some(user_agent) = &self.user_agent {
+            builder = builder.user_agent(user_agent);
+        }
+        if let Some(api_url) = &self.api_url {
+            builder = builder.url(api_url);
+        }
+        Ok(builder)
+    }
+}
+
 #[derive(Debug, StructOpt)]
 enum Config {
     RegisterDevice {
@@ -31,15 +96,125 @@ enum Config {
         device_name: String,
         #[structopt(short, long)]
         phone: String,
+        #[structopt(
+            short,
+            long,
+            help = "email used to create a new account when the phone has none yet"
+        )]
+        email: Option<String>,
+        #[structopt(
+            long,
+            help = "how to verify the registration, push, sms or call",
+            default_value = "push"
+        )]
+        via: VerificationMethod,
+        #[structopt(
+            long,
+            help = "where the backup password is kept, inline, keyring or prompt",
+            default_value = "inline"
+        )]
+        password_storage: PasswordStorage,
     },
+    UnregisterDevice {},
     ListServices {},
+    ListDevices {
+        #[structopt(short, long, help = "output type", default_value)]
+        output: Output,
+    },
     GetToken {
         #[structopt(short, long, help = "fuzzy search a service by its name")]
         service_name: String,
         #[structopt(short, long, help = "output type", default_value)]
         output: Output,
+        #[structopt(
+            long,
+            help = "refresh the cached services when they are older than this many seconds",
+            default_value = "86400"
+        )]
+        cache_ttl: u64,
+        #[structopt(
+            long,
+            help = "sync the clock with authy when the last sync is older than this many seconds",
+            default_value = "86400"
+        )]
+        time_sync_interval: u64,
+        #[structopt(
+            long,
+            help = "codes valid for less than this many seconds are replaced by the next ones"
+        )]
+        min_validity: Option<u64>,
+        #[structopt(
+            long,
+            requires = "min-validity",
+            help = "wait for the next codes instead of printing them upfront"
+        )]
+        wait: bool,
+    },
+    Verify {
+        #[structopt(
+            short,
+            long,
+            help = "service with this exact name, or the only one it fuzzy matches"
+        )]
+        service_name: String,
+        #[structopt(short, long, help = "code to verify")]
+        code: String,
+        #[structopt(
+            short,
+            long,
+            help = "how many steps before and after the current one are accepted",
+            default_value = "1"
+        )]
+        window: u32,
+    },
+    ImportHotp {
+        #[structopt(short, long)]
+        name: String,
+        #[structopt(short, long, default_value = "6")]
+        digits: usize,
+        #[structopt(long, help = "sha1, sha256 or sha512", default_value = "sha1")]
+        algorithm: OtpAlgorithm,
+        #[structopt(long, help = "counter used by the next code", default_value = "0")]
+        counter: u64,
+    },
+    SetTokenSettings {
+        #[structopt(
+            short,
+            long,
+            help = "service with this exact name, or the only one it fuzzy matches"
+        )]
+        service_name: String,
+        #[structopt(long, help = "sha1, sha256 or sha512")]
+        algorithm: Option<OtpAlgorithm>,
+        #[structopt(long, help = "seconds each code is valid for")]
+        period: Option<u64>,
+        #[structopt(
+            long,
+            help = "go back to what authy sends",
+            conflicts_with_all = &["algorithm", "period"]
+        )]
+        reset: bool,
+    },
+    SetBackupPassword {
+        #[structopt(
+            long,
+            help = "where the backup password is kept from now on, inline, keyring or prompt"
+        )]
+        password_storage: Option<PasswordStorage>,
     },
     DumpSeeds,
+    Agent {},
+    SyncTime {},
+    Approvals(Approvals),
+}
+
+/// OneTouch requests, e.g. logins, waiting for an answer from any device.
+#[derive(Debug, StructOpt)]
+enum Approvals {
+    List {
+        #[structopt(short, long, help = "output type", default_value)]
+        output: Output,
+    },
 }
 
 pub fn setup_error_handlers() -> Result<()> {
@@ -72,51 +247,113 @@ async fn main() -> Result<()> {
 
 #[instrument]
 async fn work() -> Result<()> {
-    let config = Config::from_args();
-    match config {
-        Config::RegisterDevice { phone, device_name } => {
+    let Opts {
+        connection,
+        command,
+    } = Opts::from_args();
+    let builder = connection.client_builder()?;
+    match command {
+        Config::RegisterDevice {
+            phone,
+            device_name,
+            email,
+            via,
+            password_storage,
+        } => {
+            check_password_storage(password_storage)?;
             if get_client_from_file().await.is_ok() {
                 eprintln!("You already have a registered device.");
                 exit(1);
             }
-            println!("Type your password: ");
-            let backup_password = BufReader::new(io::stdin())
-                .lines()
-                .next_line()
-                .await
-                .expect("failed to read backup password")
-                .expect("no password provided");
+            let mut input = BufReader::new(io::stdin()).lines();
+            let backup_password = prompt(&mut input, "Type your password: ").await?;
 
-            let client = get_new_client(&phone, &device_name, &backup_password).await?;
-            save_client_configuration(&client)?;
+            let mut client = get_new_client(
+                &mut input,
+                &builder,
+                &phone,
+                &device_name,
+                &backup_password,
+                email.as_deref(),
+                via,
+            )
+            .await?;
+            let backup_password =
+                confirm_backup_password(&mut input, &mut client, backup_password).await?;
+            client.set_password_storage(password_storage);
+            save_client_configuration(&client, &backup_password)?;
+        }
+        Config::UnregisterDevice {} => {
+            let mut client = get_saved_client(&builder)?;
+            client.unregister_device().await?;
+            mambembe_keyring::delete::<Cached<Vec<AuthenticatorToken>>>()
+                .wrap_err("failed to remove cached tokens")?;
+            mambembe_keyring::delete::<Cached<Vec<AuthenticatorApp>>>()
+                .wrap_err("failed to remove cached apps")?;
+            mambembe_keyring::delete::<BackupPassword>()
+                .wrap_err("failed to remove backup password")?;
+            mambembe_keyring::delete::<AuthyClient>()
+                .wrap_err("failed to remove client configuration")?;
+            println!("Device {:?} unregistered", client.device_name);
         }
         Config::ListServices {} => {
-            let client = get_saved_client()?;
+            let client = get_saved_client(&builder)?;
             let services = client.list_authenticator_tokens().await?;
+            let services_diff =
+                get_cache::<Cached<Vec<AuthenticatorToken>>>()?.map(|cache| cache.diff(&services));
             // As this is fresh, lets update our keyring
+            let services = Cached::new(client.device_id(), services);
             mambembe_keyring::set(&services)?;
 
-            for service in services {
+            let apps = client.list_authenticator_apps().await?;
+            let apps_diff =
+                get_cache::<Cached<Vec<AuthenticatorApp>>>()?.map(|cache| cache.diff(&apps));
+            let apps = Cached::new(client.device_id(), apps);
+            mambembe_keyring::set(&apps)?;
+
+            for service in services.items {
                 println!(
                     "Name: {:?} Account type: {:?}",
                     service.name, service.account_type
                 );
             }
+            for app in apps.items {
+                println!("Name: {:?} Account type: {:?}", app.name, "authy");
+            }
+            for diff in [services_diff, apps_diff].iter().flatten() {
+                print_diff(diff);
+            }
+        }
+        Config::ListDevices { output } => {
+            let client = get_saved_client(&builder)?;
+            let devices = client.list_devices().await?;
+            output.print_devices(devices)?;
         }
         Config::GetToken {
             service_name,
             output,
+            cache_ttl,
+            time_sync_interval,
+            min_validity,
+            wait,
         } => {
-            let client = get_saved_client()?;
-            let mut services: Vec<AuthenticatorToken> = match mambembe_keyring::get() {
-                Ok(services) => services,
-                Err(MambembeKeyringError::NoPasswordFound) => {
-                    let services = client.list_authenticator_tokens().await?;
-                    mambembe_keyring::set(&services).unwrap();
-                    services
+            let mut client = get_saved_client(&builder)?;
+            unlock_client(&mut client).await?;
+            if !client.is_offline() {
+                // Codes from a stale clock are still better than none
+                let interval = Duration::from_secs(time_sync_interval);
+                match client.sync_time_if_older_than(interval).await {
+                    Ok(true) => {
+                        if let Err(err) = save_time_sync(&client) {
+                            warn!("Failed to save the clock offset: {:#}", err);
+                        }
+                    }
+                    Ok(false) => {}
+                    Err(err) => warn!("Failed to sync the clock with authy: {}", err),
                 }
-                Err(err) => return Err(err.into()),
-            };
+            }
+            let cache_ttl = Duration::from_secs(cache_ttl);
+            let mut services = get_tokens(&client, cache_ttl).await?;
             let matcher = SkimMatcherV2::default();
 
             let filtered: Vec<_> = services
@@ -125,45 +362,186 @@ async fn work() -> Result<()> {
                 .collect();
 
             let mut output_data = Vec::with_capacity(filtered.len());
+            let min_validity = Duration::from_secs(min_validity.unwrap_or_default());
+            let generator = if wait {
+                client.token_generator()
+            } else {
+                client.token_generator().with_min_validity(min_validity)
+            };
 
             for service in filtered {
-                client.initialize_authenticator_token(&mut *service)?;
-                let token = client.get_otp_token(service).await?;
-                output_data.push(ServiceToken {
-                    service: service.name.clone(),
-                    token,
-                });
+                let mut code = generator.token(service)?;
+                if let Some(delay) = code.wait_for(min_validity)?.filter(|_| wait) {
+                    info!("Waiting {:?} for a fresh code of {:?}", delay, service.name);
+                    sleep(delay).await;
+                    code = generator.token(service)?;
+                }
+                output_data.push(ServiceToken::new(service.name.clone(), code));
+            }
+
+            output_data.extend(generate_hotp_codes(&service_name, &matcher)?);
+
+            for app in get_apps(&client, cache_ttl)
+                .await?
+                .iter()
+                .filter(|a| matcher.fuzzy_match(&a.name, &service_name).is_some())
+            {
+                let mut code = generator.app_token(app)?;
+                if let Some(delay) = code.wait_for(min_validity)?.filter(|_| wait) {
+                    info!("Waiting {:?} for a fresh code of {:?}", delay, app.name);
+                    sleep(delay).await;
+                    code = generator.app_token(app)?;
+                }
+                output_data.push(ServiceToken::new(app.name.clone(), code));
+            }
+            if output_data.is_empty() && client.is_offline() {
+                return Err(eyre!("no cached service matches {:?}", service_name));
             }
             output.print(output_data)?;
         }
-        Config::DumpSeeds => {
-            let client = get_saved_client()?;
-            let mut services: Vec<AuthenticatorToken> = match mambembe_keyring::get() {
-                Ok(services) => services,
-                Err(MambembeKeyringError::NoPasswordFound) => {
-                    let services = client.list_authenticator_tokens().await?;
-                    mambembe_keyring::set(&services).unwrap();
-                    services
+        Config::Verify {
+            service_name,
+            code,
+            window,
+        } => {
+            let mut client = get_saved_client(&builder)?;
+            unlock_client(&mut client).await?;
+            let mut services = get_tokens(&client, Duration::MAX).await?;
+            let service = find_one_service(&mut services, &service_name)?;
+
+            match client.token_generator().verify(service, &code, window)? {
+                Some(step) => println!("Code is valid for {:?} at step {}", service.name, step),
+                None => {
+                    eprintln!("Code is not valid for {:?}", service.name);
+                    exit(1);
                 }
-                Err(err) => return Err(err.into()),
-            };
+            }
+        }
+        Config::ImportHotp {
+            name,
+            digits,
+            algorithm,
+            counter,
+        } => {
+            let mut input = BufReader::new(io::stdin()).lines();
+            let secret = prompt(&mut input, "Type the base32 secret: ").await?;
+            let token = HotpToken::new(&name, &secret, digits, algorithm, counter)?;
+
+            let _lock = mambembe_keyring::lock("hotp")?;
+            let mut tokens = get_hotp_tokens()?;
+            if tokens.iter().any(|t| t.name == token.name) {
+                return Err(eyre!("there is already a hotp token called {:?}", name));
+            }
+            tokens.push(token);
+            mambembe_keyring::set(&tokens).wrap_err("failed to save hotp tokens")?;
+            println!("Imported {:?}", name);
+        }
+        Config::SetTokenSettings {
+            service_name,
+            algorithm,
+            period,
+            reset,
+        } => {
+            if period == Some(0) {
+                return Err(eyre!("period has to be greater than zero"));
+            }
+            let client = get_saved_client(&builder)?;
+            let mut services = get_tokens(&client, Duration::MAX).await?;
+            let service = find_one_service(&mut services, &service_name)?;
+
+            let _lock = mambembe_keyring::lock("token-settings")?;
+            let mut settings = get_token_settings()?;
+            if reset {
+                settings.remove(&service.unique_id);
+            } else {
+                let current = settings.entry(service.unique_id.clone()).or_default();
+                current.algorithm = algorithm.or(current.algorithm);
+                current.period = period.or(current.period);
+            }
+            mambembe_keyring::set(&settings).wrap_err("failed to save token settings")?;
+            let action = if reset { "reset" } else { "saved" };
+            println!("Settings of {:?} {}", service.name, action);
+        }
+        Config::SetBackupPassword { password_storage } => {
+            if let Some(password_storage) = password_storage {
+                check_password_storage(password_storage)?;
+            }
+            let mut input = BufReader::new(io::stdin()).lines();
+            let backup_password = prompt(&mut input, "Type your new password: ").await?;
+
+            let _lock = mambembe_keyring::lock("client")?;
+            let mut client = get_saved_client(&builder)?;
+            if let Some(password_storage) = password_storage {
+                client.set_password_storage(password_storage);
+            }
+            let previous =
+                mambembe_keyring::get::<AuthyClient>().wrap_err("failed to fetch saved client")?;
+            let previous_password = get_cache::<BackupPassword>()?;
+            let previous_agent_password = agent::get();
+            let tokens = client.change_backup_password(&backup_password).await?;
+            // Everything goes back to how it was if any of it can't be saved
+            let saved = save_client_configuration(&client, &backup_password).and_then(|()| {
+                mambembe_keyring::set(&Cached::new(client.device_id(), tokens))
+                    .wrap_err("failed to save tokens")
+            });
+            if let Err(err) = saved {
+                mambembe_keyring::set(&previous)
+                    .wrap_err("failed to restore the previous client configuration")?;
+                match previous_password {
+                    Some(password) => mambembe_keyring::set(&password),
+                    None => mambembe_keyring::delete::<BackupPassword>(),
+                }
+                .wrap_err("failed to restore the previous backup password")?;
+                if client.password_storage() == PasswordStorage::Prompt {
+                    // An empty password is the same as the agent not knowing it
+                    agent::set(
+                        previous_agent_password
+                            .as_ref()
+                            .map_or("", |password| password.expose()),
+                    );
+                }
+                return Err(err).wrap_err("backup password not changed");
+            }
+            println!("Backup password changed");
+        }
+        Config::DumpSeeds => {
+            let mut client = get_saved_client(&builder)?;
+            unlock_client(&mut client).await?;
+            let mut services = get_tokens(&client, Duration::MAX).await?;
             for service in services.iter_mut() {
                 client.initialize_authenticator_token(service)?;
                 println!("Servie: {} Seed: {}", service.name, &service.dump_seed()?);
             }
+            for app in get_apps(&client, Duration::MAX).await? {
+                println!("Servie: {} Seed: {}", app.name, &app.dump_seed()?);
+            }
+            for token in get_hotp_tokens()? {
+                println!(
+                    "Servie: {} Seed: {} Counter: {}",
+                    token.name,
+                    token.dump_seed(),
+                    token.counter
+                );
+            }
+        }
+        Config::Agent {} => agent::serve().await?,
+        Config::SyncTime {} => {
+            let mut client = get_saved_client(&builder)?;
+            client.sync_time_with_server().await?;
+            save_time_sync(&client)?;
+            let offset = client.time_sync().map_or(0, |time_sync| time_sync.offset());
+            match offset {
+                0 => println!("Clock is in sync with authy"),
+                offset if offset > 0 => println!("Clock is {}s behind authy", offset),
+                offset => println!("Clock is {}s ahead of authy", -offset),
+            }
+        }
+        Config::Approvals(Approvals::List { output }) => {
+            let client = get_saved_client(&builder)?;
+            let requests = client.list_approval_requests().await?;
+            output.print_approval_requests(requests)?;
         }
     }
-
-    // client.check_current_device().await?;
-    // client.sync_time_with_server().await?;
-    // // client.check_current_device_keys().await?;
-    // client.fetch_private_keys().await?;
-    // save_client_configuration(&client).await?;
-    // let services = client.list_authenticator_tokens().await?;
-    // for token in services {
-    //     println!("{:#?}", token);
-    //     println!("{:?} -> {:?}", token.name, client.get_otp_token(&token)?);
-    // }
     Ok(())
 }
 
@@ -174,44 +552,338 @@ async fn get_client_from_file() -> Result<AuthyClient> {
         .wrap_err("failed to get client from a file")
 }
 
-#[instrument(skip(backup_password))]
+#[instrument(skip(input, builder, backup_password))]
 async fn get_new_client(
+    input: &mut Input,
+    builder: &AuthyClientBuilder,
     phone: &str,
     device_name: &str,
     backup_password: &str,
+    email: Option<&str>,
+    via: VerificationMethod,
 ) -> Result<AuthyClient> {
-    let mut client = AuthyClient::new(device_name, backup_password)?;
+    let mut client = builder.clone().build(device_name, backup_password)?;
     match client.check_user_status(phone).await? {
         CheckStatusResponse::RegisterDevice => {}
         CheckStatusResponse::RegisterAccount => {
-            eprintln!("Account registration is not implemented yet");
-            exit(1);
+            let email = match email {
+                Some(email) => email,
+                None => {
+                    eprintln!(
+                        "There is no account for this phone yet, pass --email to create one."
+                    );
+                    exit(1);
+                }
+            };
+            let (country_code, cellphone) = phone.split_once('-').ok_or_else(|| {
+                eyre!("phone has to be in the <country code>-<number> format e.g.: 49-123456")
+            })?;
+            // A new account has no other device to approve a push
+            let via = match via {
+                VerificationMethod::Push => VerificationMethod::Sms,
+                via => via,
+            };
+            client
+                .create_account(email, country_code, cellphone, via)
+                .await?;
+
+            let code = prompt(input, "Type the verification code you received: ").await?;
+            client.complete_registration(&code).await?;
+            return Ok(client);
         }
     }
 
-    let RegisterDeviceResponse::RegistrationPending(request_id) = client.register_device().await?;
+    let RegisterDeviceResponse::RegistrationPending {
+        request_id,
+        approval_pin,
+        provider,
+    } = client.register_device(via).await?;
 
-    let pin = loop {
-        let response = client.check_registration(&request_id).await?;
+    let pin = if via == VerificationMethod::Push {
+        if let Some(approval_pin) = approval_pin {
+            println!(
+                "Approve the request on your other device, it should show the pin {}",
+                approval_pin
+            );
+        }
+        loop {
+            let response = client.check_registration(&request_id).await?;
 
-        match response {
-            CheckRegistrationStatus::Accepted(pin) => break pin,
-            CheckRegistrationStatus::Pending => {}
-        };
-        info!("Waiting for device registration");
-        sleep(Duration::from_secs(10)).await;
+            match response {
+                CheckRegistrationStatus::Accepted(pin) => break pin,
+                CheckRegistrationStatus::Pending => {}
+            };
+            info!("Waiting for device registration");
+            sleep(Duration::from_secs(10)).await;
+        }
+    } else {
+        let message = format!("Type the pin you received via {}: ", provider);
+        prompt(input, &message).await?
     };
 
     client.complete_registration(&pin).await?;
-
-    save_client_configuration(&client)?;
     Ok(client)
 }
 
-fn save_client_configuration(client: &AuthyClient) -> Result<()> {
+/// Asks for the backup password again until it decrypts the tokens, a typo
+/// would otherwise only show up when getting a token. Returns the password
+/// that worked.
+async fn confirm_backup_password(
+    input: &mut Input,
+    client: &mut AuthyClient,
+    mut backup_password: String,
+) -> Result<String> {
+    loop {
+        match client.verify_backup_password().await {
+            Ok(()) => return Ok(backup_password),
+            Err(MambembeError::InvalidBackupPassword) => {
+                eprintln!("The backup password can't decrypt your tokens.");
+                backup_password = prompt(input, "Type your password again: ").await?;
+                client.set_backup_password(&backup_password);
+            }
+            Err(err) => return Err(err).wrap_err("failed to verify the backup password"),
+        }
+    }
+}
+
+/// The message goes to stderr, so it doesn't end up mixed with the output.
+async fn prompt(input: &mut Input, message: &str) -> Result<String> {
+    eprintln!("{}", message);
+    input
+        .next_line()
+        .await
+        .wrap_err("failed to read from stdin")?
+        .ok_or_else(|| eyre!("no input provided"))
+}
+
+/// Unless it is inline, the backup password goes to its own keyring entry
+/// or to the agent depending on the client's password storage.
+fn save_client_configuration(client: &AuthyClient, backup_password: &str) -> Result<()> {
+    match client.password_storage() {
+        PasswordStorage::Keyring => {
+            mambembe_keyring::set(&BackupPassword(backup_password.to_string().into()))
+                .wrap_err("failed to save backup password")?
+        }
+        PasswordStorage::Inline | PasswordStorage::Prompt => {
+            mambembe_keyring::delete::<BackupPassword>()
+                .wrap_err("failed to remove backup password")?
+        }
+    }
+    if client.password_storage() == PasswordStorage::Prompt {
+        agent::set(backup_password);
+    }
     mambembe_keyring::set(client).wrap_err("failed to save client configuration")
 }
 
-fn get_saved_client() -> Result<AuthyClient> {
-    mambembe_keyring::get::<AuthyClient>().wrap_err("failed to fetch saved client")
+/// Only the time sync is saved, the rest of the saved client may have been
+/// changed by another command in the meantime.
+fn save_time_sync(client: &AuthyClient) -> Result<()> {
+    let _lock = mambembe_keyring::lock("client")?;
+    let mut saved =
+        mambembe_keyring::get::<AuthyClient>().wrap_err("failed to fetch saved client")?;
+    saved.set_time_sync(client.time_sync().cloned());
+    mambembe_keyring::set(&saved).wrap_err("failed to save client configuration")
+}
+
+fn check_password_storage(password_storage: PasswordStorage) -> Result<()> {
+    if password_storage == PasswordStorage::Keyring && !mambembe_keyring::OS_KEYRING {
+        return Err(eyre!(
+            "this build saves to plain files, the keyring password storage needs the with-keyring feature"
+        ));
+    }
+    Ok(())
+}
+
+/// Fills in the backup password when it is not saved with the client, only
+/// needed before decrypting tokens.
+async fn unlock_client(client: &mut AuthyClient) -> Result<()> {
+    let backup_password = match client.password_storage() {
+        PasswordStorage::Inline => return Ok(()),
+        PasswordStorage::Keyring => {
+            let BackupPassword(backup_password) =
+                mambembe_keyring::get().wrap_err("failed to fetch backup password")?;
+            backup_password
+        }
+        PasswordStorage::Prompt => match agent::get() {
+            Some(backup_password) => backup_password,
+            None => {
+                let mut input = BufReader::new(io::stdin()).lines();
+                let backup_password =
+                    Secret::new(prompt(&mut input, "Type your password: ").await?);
+                agent::set(backup_password.expose());
+                backup_password
+            }
+        },
+    };
+    client.set_backup_password(backup_password.expose());
+    Ok(())
+}
+
+fn get_saved_client(builder: &AuthyClientBuilder) -> Result<AuthyClient> {
+    let client = mambembe_keyring::get::<AuthyClient>().wrap_err("failed to fetch saved client")?;
+    builder
+        .clone()
+        .configure(client)
+        .wrap_err("failed to configure http client")
+}
+
+fn get_cache<T>() -> Result<Option<T>>
+where
+    T: DeserializeOwned + Data<T>,
+{
+    match mambembe_keyring::get() {
+        Ok(cache) => Ok(Some(cache)),
+        Err(MambembeKeyringError::NoPasswordFound) => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Uses the cache when it is fresh enough or when offline, otherwise
+/// refreshes it.
+async fn get_tokens(client: &AuthyClient, ttl: Duration) -> Result<Vec<AuthenticatorToken>> {
+    let mut tokens = match get_cache::<Cached<Vec<AuthenticatorToken>>>()? {
+        Some(cache) if client.is_offline() || cache.is_fresh(client.device_id(), ttl) => {
+            cache.items
+        }
+        _ if client.is_offline() => {
+            return Err(eyre!(
+                "tokens are not cached yet, run list-services while online"
+            ))
+        }
+        stale => {
+            info!("Refreshing cached tokens");
+            match client.list_authenticator_tokens().await {
+                Ok(tokens) => {
+                    let cache = Cached::new(client.device_id(), tokens);
+                    mambembe_keyring::set(&cache)?;
+                    cache.items
+                }
+                Err(err) => match stale {
+                    Some(cache) if cache.is_usable_for(client.device_id()) => {
+                        warn!("Failed to refresh tokens, using the cached ones: {}", err);
+                        cache.items
+                    }
+                    _ => return Err(err.into()),
+                },
+            }
+        }
+    };
+    let settings = get_token_settings()?;
+    for token in tokens.iter_mut() {
+        if let Some(settings) = settings.get(&token.unique_id) {
+            token.apply_settings(settings);
+        }
+    }
+    Ok(tokens)
+}
+
+/// Prefers a service named exactly `service_name` (ignoring case) and only
+/// falls back to fuzzy matching when there is none.
+fn find_one_service<'a>(
+    services: &'a mut [AuthenticatorToken],
+    service_name: &str,
+) -> Result<&'a mut AuthenticatorToken> {
+    if let Some(position) = services
+        .iter()
+        .position(|t| t.name.eq_ignore_ascii_case(service_name))
+    {
+        return Ok(&mut services[position]);
+    }
+    let matcher = SkimMatcherV2::default();
+    let mut filtered: Vec<_> = services
+        .iter_mut()
+        .filter(|t| matcher.fuzzy_match(&t.name, service_name).is_some())
+        .collect();
+    if filtered.len() != 1 {
+        let names: Vec<_> = filtered.iter().map(|t| &t.name).collect();
+        return Err(eyre!(
+            "{:?} has to match exactly one service, it matched {:?}",
+            service_name,
+            names
+        ));
+    }
+    Ok(filtered.remove(0))
+}
+
+fn get_token_settings() -> Result<BTreeMap<String, TokenSettings>> {
+    Ok(get_cache::<BTreeMap<String, TokenSettings>>()?.unwrap_or_default())
+}
+
+async fn get_apps(client: &AuthyClient, ttl: Duration) -> Result<Vec<AuthenticatorApp>> {
+    match get_cache::<Cached<Vec<AuthenticatorApp>>>()? {
+        Some(cache) if client.is_offline() || cache.is_fresh(client.device_id(), ttl) => {
+            Ok(cache.items)
+        }
+        _ if client.is_offline() => {
+            warn!("Apps are not cached yet, run list-services while online to use them offline");
+            Ok(Vec::new())
+        }
+        stale => {
+            info!("Refreshing cached apps");
+            match client.list_authenticator_apps().await {
+                Ok(apps) => {
+                    let cache = Cached::new(client.device_id(), apps);
+                    mambembe_keyring::set(&cache)?;
+                    Ok(cache.items)
+                }
+                Err(err) => match stale {
+                    Some(cache) if cache.is_usable_for(client.device_id()) => {
+                        warn!("Failed to refresh apps, using the cached ones: {}", err);
+                        Ok(cache.items)
+                    }
+                    _ => Err(err.into()),
+                },
+            }
+        }
+    }
+}
+
+fn get_hotp_tokens() -> Result<Vec<HotpToken>> {
+    Ok(get_cache::<Vec<HotpToken>>()?.unwrap_or_default())
+}
+
+/// Every code burns a counter, so unlike the other tokens counter based ones
+/// are only picked when `service_name` is their exact name, ignoring case.
+/// The moved counters are saved before any code is returned, so the same
+/// code is never shown twice.
+fn generate_hotp_codes(service_name: &str, matcher: &SkimMatcherV2) -> Result<Vec<ServiceToken>> {
+    let is_selected = |t: &HotpToken| t.name.eq_ignore_ascii_case(service_name);
+    let tokens = get_hotp_tokens()?;
+    for token in tokens
+        .iter()
+        .filter(|t| !is_selected(t) && matcher.fuzzy_match(&t.name, service_name).is_some())
+    {
+        info!(
+            "Skipping counter based {:?}, pass its exact name to get a code",
+            token.name
+        );
+    }
+    if !tokens.iter().any(is_selected) {
+        return Ok(vec![]);
+    }
+
+    let _lock = mambembe_keyring::lock("hotp")?;
+    // Read again as another process may have moved the counters meanwhile
+    let mut tokens = get_hotp_tokens()?;
+    let codes: Vec<_> = tokens
+        .iter_mut()
+        .filter(|t| is_selected(t))
+        .map(|t| ServiceToken::counter_based(t.name.clone(), t.generate()))
+        .collect();
+    if !codes.is_empty() {
+        mambembe_keyring::set(&tokens).wrap_err("failed to save hotp counters")?;
+    }
+    Ok(codes)
+}
+
+fn print_diff(diff: &CacheDiff) {
+    for name in &diff.added {
+        println!("Added: {:?}", name);
+    }
+    for name in &diff.removed {
+        println!("Removed: {:?}", name);
+    }
+    for (old_name, new_name) in &diff.renamed {
+        println!("Renamed: {:?} -> {:?}", old_name, new_name);
+    }
 }
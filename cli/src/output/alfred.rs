@@ -1,5 +1,6 @@
 use std::str::FromStr;
 
+use mambembe_lib::models::{ApprovalRequest, DeviceInfo};
 use serde::Serialize;
 
 use crate::{output::ServiceToken, Output};
@@ -12,6 +13,8 @@ pub(crate) struct Alfred {
 #[derive(Debug, Serialize)]
 pub(crate) struct AlfredItem {
     title: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subtitle: Option<String>,
     #[serde(rename = "arg")]
     token: String,
 }
@@ -22,12 +25,45 @@ impl Alfred {
             items: data
                 .into_iter()
                 .map(|item| AlfredItem {
+                    subtitle: Some(match (item.seconds_remaining, item.next_token) {
+                        (Some(seconds_remaining), Some(next_token)) => format!(
+                            "Expires in {}s, next token {}",
+                            seconds_remaining, next_token
+                        ),
+                        _ => "Counter based, valid only once".to_string(),
+                    }),
                     title: item.service,
                     token: item.token,
                 })
                 .collect(),
         }
     }
+
+    pub fn from_devices(data: Vec<DeviceInfo>) -> Self {
+        Self {
+            items: data
+                .into_iter()
+                .map(|device| AlfredItem {
+                    title: device.name,
+                    subtitle: None,
+                    token: device.id,
+                })
+                .collect(),
+        }
+    }
+
+    pub fn from_approval_requests(data: Vec<ApprovalRequest>) -> Self {
+        Self {
+            items: data
+                .into_iter()
+                .map(|request| AlfredItem {
+                    title: request.message,
+                    subtitle: Some(request.app_name),
+                    token: request.uuid,
+                })
+                .collect(),
+        }
+    }
 }
 
 impl FromStr for Output {
@@ -1,6 +1,7 @@
 mod alfred;
 
 use color_eyre::Result;
+use mambembe_lib::models::{ApprovalRequest, DeviceInfo, OtpCode};
 use serde::{Deserialize, Serialize};
 
 use crate::output::alfred::Alfred;
@@ -9,6 +10,32 @@ use crate::output::alfred::Alfred;
 pub struct ServiceToken {
     pub(crate) service: String,
     pub(crate) token: String,
+    /// None for counter based tokens.
+    pub(crate) next_token: Option<String>,
+    pub(crate) period: Option<u64>,
+    pub(crate) seconds_remaining: Option<u64>,
+}
+
+impl ServiceToken {
+    pub fn new(service: String, code: OtpCode) -> Self {
+        Self {
+            service,
+            token: code.code,
+            next_token: Some(code.next_code),
+            period: Some(code.period),
+            seconds_remaining: Some(code.seconds_remaining),
+        }
+    }
+
+    pub fn counter_based(service: String, token: String) -> Self {
+        Self {
+            service,
+            token,
+            next_token: None,
+            period: None,
+            seconds_remaining: None,
+        }
+    }
 }
 
 #[derive(Default, Debug, Serialize, Deserialize)]
@@ -24,9 +51,46 @@ impl Output {
         match self {
             Output::PlainText => {
                 for item in data {
+                    match item.seconds_remaining {
+                        Some(seconds_remaining) => println!(
+                            "Service: {:?} Token: {:?} Type: {:#?} Expires in: {}s",
+                            item.service, item.token, 1, seconds_remaining
+                        ),
+                        None => println!(
+                            "Service: {:?} Token: {:?} Type: {:#?}",
+                            item.service, item.token, 1
+                        ),
+                    }
+                }
+            }
+            Output::Json => {
+                println!("{}", serde_json::to_string_pretty(&data)?)
+            }
+            Output::Alfred => {
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&Alfred::from_service_token(data))?
+                )
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn print_devices(&self, data: Vec<DeviceInfo>) -> Result<()> {
+        match self {
+            Output::PlainText => {
+                for device in data {
                     println!(
-                        "Service: {:?} Token: {:?} Type: {:#?}",
-                        item.service, item.token, 1
+                        "Name: {:?} App: {:?} Type: {:?} City: {:?} Country: {:?} Created at: {:?} Registered: {:?} Id: {:?}",
+                        device.name,
+                        device.app,
+                        device.device_type,
+                        device.city,
+                        device.country,
+                        device.created_at,
+                        device.registered,
+                        device.id
                     )
                 }
             }
@@ -36,7 +100,36 @@ impl Output {
             Output::Alfred => {
                 println!(
                     "{}",
-                    serde_json::to_string_pretty(&Alfred::from_service_token(data))?
+                    serde_json::to_string_pretty(&Alfred::from_devices(data))?
+                )
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn print_approval_requests(&self, data: Vec<ApprovalRequest>) -> Result<()> {
+        match self {
+            Output::PlainText => {
+                for request in data {
+                    println!(
+                        "App: {:?} Message: {:?} Details: {:?} Created at: {:?} Expires at: {:?} Id: {:?}",
+                        request.app_name,
+                        request.message,
+                        request.details,
+                        request.created_at,
+                        request.expires_at,
+                        request.uuid
+                    )
+                }
+            }
+            Output::Json => {
+                println!("{}", serde_json::to_string_pretty(&data)?)
+            }
+            Output::Alfred => {
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&Alfred::from_approval_requests(data))?
                 )
             }
         }
@@ -0,0 +1,209 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use mambembe_lib::models::{AuthenticatorApp, AuthenticatorToken};
+use serde::{Deserialize, Serialize};
+
+/// Data fetched from authy together with when and for which device it was
+/// fetched, so stale caches can be noticed.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(from = "StoredCache<T>")]
+pub struct Cached<T> {
+    pub fetched_at: u64,
+    pub device_id: Option<u64>,
+    pub items: T,
+}
+
+/// Before the metadata existed only the items were stored, those are
+/// treated as already expired.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredCache<T> {
+    Cached {
+        fetched_at: u64,
+        device_id: Option<u64>,
+        items: T,
+    },
+    Legacy(T),
+}
+
+impl<T> From<StoredCache<T>> for Cached<T> {
+    fn from(stored: StoredCache<T>) -> Self {
+        match stored {
+            StoredCache::Cached {
+                fetched_at,
+                device_id,
+                items,
+            } => Self {
+                fetched_at,
+                device_id,
+                items,
+            },
+            StoredCache::Legacy(items) => Self {
+                fetched_at: 0,
+                device_id: None,
+                items,
+            },
+        }
+    }
+}
+
+impl<T> Cached<T> {
+    pub fn new(device_id: Option<u64>, items: T) -> Self {
+        Self {
+            fetched_at: now(),
+            device_id,
+            items,
+        }
+    }
+
+    /// Fresh means it was fetched for the same device less than `ttl` ago.
+    pub fn is_fresh(&self, device_id: Option<u64>, ttl: Duration) -> bool {
+        self.device_id.is_some()
+            && self.device_id == device_id
+            && now().saturating_sub(self.fetched_at) < ttl.as_secs()
+    }
+
+    /// Whether it can stand in when refreshing fails, legacy caches don't
+    /// know their device so they are assumed to be from the current one.
+    pub fn is_usable_for(&self, device_id: Option<u64>) -> bool {
+        self.device_id.is_none() || self.device_id == device_id
+    }
+}
+
+/// Something that can be compared between two versions of a cache.
+pub trait CacheItem {
+    /// Stays the same even if the item is renamed.
+    fn cache_key(&self) -> &str;
+    fn display_name(&self) -> &str;
+}
+
+impl CacheItem for AuthenticatorToken {
+    fn cache_key(&self) -> &str {
+        &self.unique_id
+    }
+
+    fn display_name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl CacheItem for AuthenticatorApp {
+    fn cache_key(&self) -> &str {
+        &self.app_id
+    }
+
+    fn display_name(&self) -> &str {
+        &self.name
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct CacheDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    /// Old and new names.
+    pub renamed: Vec<(String, String)>,
+}
+
+impl CacheDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.renamed.is_empty()
+    }
+}
+
+impl<T: CacheItem> Cached<Vec<T>> {
+    pub fn diff(&self, new_items: &[T]) -> CacheDiff {
+        let old: HashMap<_, _> = self
+            .items
+            .iter()
+            .map(|item| (item.cache_key(), item.display_name()))
+            .collect();
+        let new: HashMap<_, _> = new_items
+            .iter()
+            .map(|item| (item.cache_key(), item.display_name()))
+            .collect();
+
+        let mut diff = CacheDiff::default();
+        for item in new_items {
+            match old.get(item.cache_key()) {
+                None => diff.added.push(item.display_name().to_string()),
+                Some(&old_name) if old_name != item.display_name() => diff
+                    .renamed
+                    .push((old_name.to_string(), item.display_name().to_string())),
+                Some(_) => {}
+            }
+        }
+        for item in &self.items {
+            if !new.contains_key(item.cache_key()) {
+                diff.removed.push(item.display_name().to_string());
+            }
+        }
+        diff
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::cache::{CacheDiff, CacheItem, Cached};
+
+    struct Item(&'static str, &'static str);
+
+    impl CacheItem for Item {
+        fn cache_key(&self) -> &str {
+            self.0
+        }
+
+        fn display_name(&self) -> &str {
+            self.1
+        }
+    }
+
+    #[test]
+    fn legacy_cache_is_expired() {
+        let cached: Cached<Vec<u32>> = serde_json::from_str("[1, 2]").unwrap();
+        assert_eq!(cached.items, vec![1, 2]);
+        assert!(!cached.is_fresh(None, Duration::from_secs(u64::MAX)));
+        assert!(cached.is_usable_for(Some(1)));
+    }
+
+    #[test]
+    fn cache_is_fresh_only_for_the_same_device() {
+        let cached = Cached::new(Some(1), vec![1]);
+        let ttl = Duration::from_secs(60);
+        assert!(cached.is_fresh(Some(1), ttl));
+        assert!(!cached.is_fresh(Some(2), ttl));
+        assert!(!cached.is_fresh(Some(1), Duration::ZERO));
+        assert!(cached.is_usable_for(Some(1)));
+        assert!(!cached.is_usable_for(Some(2)));
+
+        let cached: Cached<Vec<u32>> =
+            serde_json::from_str(&serde_json::to_string(&cached).unwrap()).unwrap();
+        assert!(cached.is_fresh(Some(1), ttl));
+    }
+
+    #[test]
+    fn diff_detects_added_removed_and_renamed() {
+        let cached = Cached::new(Some(1), vec![Item("1", "GitHub"), Item("2", "Gitlab")]);
+        let diff = cached.diff(&[Item("1", "GitHub work"), Item("3", "AWS")]);
+        assert_eq!(
+            diff,
+            CacheDiff {
+                added: vec!["AWS".to_string()],
+                removed: vec!["Gitlab".to_string()],
+                renamed: vec![("GitHub".to_string(), "GitHub work".to_string())],
+            }
+        );
+        assert!(cached.diff(&cached.items).is_empty());
+    }
+}
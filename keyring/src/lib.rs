@@ -1,19 +1,33 @@
+mod cache;
 #[cfg(feature = "without-keyring")]
 mod local;
+mod lock;
+mod runtime;
 
-use std::result;
+use std::{collections::BTreeMap, io, path::PathBuf, result};
 
 #[cfg(feature = "with-keyring")]
 use keyring::{Entry as Keyring, Error as KeyringError};
 use lazy_static::lazy_static;
-use mambembe_lib::{models::AuthenticatorToken, AuthyClient};
-use serde::{de::DeserializeOwned, Serialize};
+use mambembe_lib::{
+    models::{AuthenticatorApp, AuthenticatorToken, HotpToken, TokenSettings},
+    secret::Secret,
+    AuthyClient,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::{from_str, to_string_pretty};
 use thiserror::Error;
 use tracing::instrument;
 
 #[cfg(feature = "without-keyring")]
 use crate::local::{Keyring, KeyringError};
+#[cfg(unix)]
+pub use crate::runtime::current_uid;
+pub use crate::{
+    cache::{CacheDiff, CacheItem, Cached},
+    lock::{lock, Lock},
+    runtime::runtime_dir,
+};
 
 const SERVICE_NAME: &str = "mambembe";
 lazy_static! {
@@ -21,8 +35,25 @@ lazy_static! {
         Keyring::new(SERVICE_NAME, "devices.json").expect("failed to create keyring");
     static ref TOKENS: Keyring =
         Keyring::new(SERVICE_NAME, "tokens.json").expect("failed to create keyring");
+    static ref APPS: Keyring =
+        Keyring::new(SERVICE_NAME, "apps.json").expect("failed to create keyring");
+    static ref HOTP: Keyring =
+        Keyring::new(SERVICE_NAME, "hotp.json").expect("failed to create keyring");
+    static ref BACKUP_PASSWORD: Keyring =
+        Keyring::new(SERVICE_NAME, "backup_password.json").expect("failed to create keyring");
+    static ref TOKEN_SETTINGS: Keyring =
+        Keyring::new(SERVICE_NAME, "token_settings.json").expect("failed to create keyring");
 }
 
+/// Whether the data ends up in the OS keyring instead of plain files.
+pub const OS_KEYRING: bool = cfg!(feature = "with-keyring");
+
+/// The backup password when it is not saved together with the client, see
+/// `PasswordStorage::Keyring`.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct BackupPassword(pub Secret<String>);
+
 type Result<T> = result::Result<T, MambembeKeyringError>;
 
 #[derive(Debug, Error)]
@@ -33,6 +64,14 @@ pub enum MambembeKeyringError {
     DeserializationError(#[from] serde_json::Error),
     #[error("unknown keyring backend error")]
     UnknownBackendError(#[from] KeyringError),
+    #[error("{0:?} is held by another process, remove it if that is not the case")]
+    Locked(PathBuf),
+    #[error("failed to create lock file")]
+    LockError(#[source] io::Error),
+    #[error("could not determine the runtime directory")]
+    RuntimeDirectoryNotFound,
+    #[error("{0:?} has to be a directory only the current user can access")]
+    InsecureRuntimeDirectory(PathBuf, #[source] io::Error),
 }
 
 pub trait Data<T> {
@@ -46,13 +85,42 @@ impl<T> Data<T> for AuthyClient {
     }
 }
 
-impl<T> Data<T> for Vec<AuthenticatorToken> {
+impl<T> Data<T> for Cached<Vec<AuthenticatorToken>> {
     #[instrument]
     fn get_keyring() -> &'static Keyring {
         &TOKENS
     }
 }
 
+impl<T> Data<T> for Cached<Vec<AuthenticatorApp>> {
+    #[instrument]
+    fn get_keyring() -> &'static Keyring {
+        &APPS
+    }
+}
+
+impl<T> Data<T> for Vec<HotpToken> {
+    #[instrument]
+    fn get_keyring() -> &'static Keyring {
+        &HOTP
+    }
+}
+
+impl<T> Data<T> for BackupPassword {
+    #[instrument]
+    fn get_keyring() -> &'static Keyring {
+        &BACKUP_PASSWORD
+    }
+}
+
+/// By the token's `unique_id`.
+impl<T> Data<T> for BTreeMap<String, TokenSettings> {
+    #[instrument]
+    fn get_keyring() -> &'static Keyring {
+        &TOKEN_SETTINGS
+    }
+}
+
 #[instrument]
 pub fn get<T>() -> Result<T>
 where
@@ -77,3 +145,15 @@ where
         .map_err(MambembeKeyringError::UnknownBackendError)?;
     Ok(())
 }
+
+/// Removes the stored data, not having anything stored is not an error.
+#[instrument]
+pub fn delete<T>() -> Result<()>
+where
+    T: Data<T>,
+{
+    match T::get_keyring().delete_password() {
+        Ok(()) | Err(KeyringError::NoEntry) => Ok(()),
+        Err(err) => Err(MambembeKeyringError::UnknownBackendError(err)),
+    }
+}
@@ -45,6 +45,14 @@ impl Keyring {
         Ok(fs::write(file_name, password.as_bytes())?)
     }
 
+    pub fn delete_password(&self) -> Result<()> {
+        let file_name = self.get_storage_file()?;
+        fs::remove_file(&file_name).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => KeyringError::NoEntry,
+            _ => e.into(),
+        })
+    }
+
     fn get_storage_file(&self) -> Result<PathBuf> {
         Ok(self
             .get_project_directory()?
@@ -0,0 +1,138 @@
+use std::{
+    convert::TryFrom,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process,
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use tracing::{instrument, warn};
+
+use crate::{runtime::runtime_dir, MambembeKeyringError, Result};
+
+const LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Lock shared between processes, released when dropped.
+#[derive(Debug)]
+pub struct Lock {
+    path: PathBuf,
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Waits until no other process holds the lock called `name`, locks left
+/// behind by processes that are gone are taken over.
+pub fn lock(name: &str) -> Result<Lock> {
+    lock_with_timeout(name, LOCK_TIMEOUT)
+}
+
+#[instrument]
+fn lock_with_timeout(name: &str, timeout: Duration) -> Result<Lock> {
+    let path = runtime_dir()?.join(format!("{}.lock", name));
+    let started = Instant::now();
+    loop {
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(mut file) => {
+                write!(file, "{}", process::id()).map_err(MambembeKeyringError::LockError)?;
+                return Ok(Lock { path });
+            }
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                if let Some(pid) = dead_holder(&path) {
+                    // Checked again so a lock taken meanwhile is not removed
+                    if dead_holder(&path) == Some(pid) {
+                        warn!("Taking over {:?} from process {}, which is gone", path, pid);
+                        match fs::remove_file(&path) {
+                            Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                                return Err(MambembeKeyringError::LockError(err))
+                            }
+                            _ => {}
+                        }
+                    }
+                    continue;
+                }
+                if started.elapsed() >= timeout {
+                    return Err(MambembeKeyringError::Locked(path));
+                }
+                sleep(RETRY_INTERVAL);
+            }
+            Err(err) => return Err(MambembeKeyringError::LockError(err)),
+        }
+    }
+}
+
+/// The pid written to the lock file when that process is not running,
+/// `None` while the file is still empty.
+fn dead_holder(path: &Path) -> Option<u32> {
+    let pid = fs::read_to_string(path).ok()?.trim().parse().ok()?;
+    if is_running(pid) {
+        None
+    } else {
+        Some(pid)
+    }
+}
+
+#[cfg(unix)]
+fn is_running(pid: u32) -> bool {
+    let pid = match libc::pid_t::try_from(pid) {
+        Ok(pid) => pid,
+        Err(_) => return false,
+    };
+    // SAFETY: signal 0 is never delivered, it only checks the process exists
+    let result = unsafe { libc::kill(pid, 0) };
+    result == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Without a way to check, the lock is only released by its holder.
+#[cfg(not(unix))]
+fn is_running(_pid: u32) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        process::{self, Command},
+        time::Duration,
+    };
+
+    use crate::{lock::lock_with_timeout, runtime::runtime_dir, MambembeKeyringError};
+
+    #[test]
+    fn lock_is_exclusive_until_dropped() {
+        let name = format!("test-{}", process::id());
+        let lock = lock_with_timeout(&name, Duration::ZERO).unwrap();
+        assert!(matches!(
+            lock_with_timeout(&name, Duration::from_millis(200)),
+            Err(MambembeKeyringError::Locked(_))
+        ));
+        drop(lock);
+        lock_with_timeout(&name, Duration::ZERO).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn lock_of_a_dead_process_is_taken_over() {
+        let mut child = Command::new("true").spawn().unwrap();
+        let dead_pid = child.id();
+        child.wait().unwrap();
+
+        let name = format!("test-dead-{}", process::id());
+        let path = runtime_dir().unwrap().join(format!("{}.lock", name));
+        fs::write(&path, dead_pid.to_string()).unwrap();
+        let lock = lock_with_timeout(&name, Duration::ZERO).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            process::id().to_string()
+        );
+        drop(lock);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,74 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use directories::ProjectDirs;
+
+use crate::{MambembeKeyringError, Result, SERVICE_NAME};
+
+/// Directory for files that only matter while mambembe is running, e.g.
+/// locks. It is created if needed and only the current user can access it.
+pub fn runtime_dir() -> Result<PathBuf> {
+    let dirs = ProjectDirs::from("com", "Jayson Reis", SERVICE_NAME)
+        .ok_or(MambembeKeyringError::RuntimeDirectoryNotFound)?;
+    let dir = dirs
+        .runtime_dir()
+        .unwrap_or_else(|| dirs.data_local_dir())
+        .to_path_buf();
+    make_private(&dir)
+        .map_err(|err| MambembeKeyringError::InsecureRuntimeDirectory(dir.clone(), err))?;
+    Ok(dir)
+}
+
+/// The effective user id of this process.
+#[cfg(unix)]
+pub fn current_uid() -> u32 {
+    // SAFETY: geteuid has no preconditions and can't fail
+    unsafe { libc::geteuid() }
+}
+
+#[cfg(unix)]
+fn make_private(dir: &Path) -> io::Result<()> {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+
+    // Only the directory itself is private, parents like ~/.local/share are
+    // left as usual
+    if let Some(parent) = dir.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match fs::DirBuilder::new().mode(0o700).create(dir) {
+        Err(err) if err.kind() != io::ErrorKind::AlreadyExists => return Err(err),
+        _ => {}
+    }
+    let metadata = fs::symlink_metadata(dir)?;
+    if !metadata.is_dir() || metadata.uid() != current_uid() {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "not a directory owned by the current user",
+        ));
+    }
+    if metadata.mode() & 0o077 != 0 {
+        fs::set_permissions(dir, fs::Permissions::from_mode(0o700))?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn make_private(dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(dir)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{fs, os::unix::fs::MetadataExt};
+
+    use crate::runtime::{current_uid, runtime_dir};
+
+    #[test]
+    fn runtime_dir_is_private() {
+        let metadata = fs::metadata(runtime_dir().unwrap()).unwrap();
+        assert_eq!(metadata.uid(), current_uid());
+        assert_eq!(metadata.mode() & 0o777, 0o700);
+    }
+}
@@ -1,10 +1,17 @@
+use std::{collections::BTreeMap, fmt};
+
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
 use crate::{
     client::{AuthyId, TimeSync},
     constants::{API_KEY, DEFAULT_LOCALE, DEVICE_APP_NAME},
-    models::{AuthenticatorToken, Device},
+    error::{MambembeError, Result},
+    models::{
+        ApprovalRequest, ApprovalStatus, AuthenticatorApp, AuthenticatorToken, Device, DeviceInfo,
+        VerificationMethod,
+    },
+    secret::REDACTED,
 };
 
 #[derive(Debug, Deserialize)]
@@ -22,11 +29,26 @@ pub(crate) struct AuthyCheckStatusResponse {
     success: bool,
 }
 
+#[derive(Debug, Serialize)]
+pub(crate) struct AuthyCreateAccountRequest {
+    pub api_key: String,
+    pub locale: String,
+    pub email: String,
+    pub cellphone: String,
+    pub country_code: String,
+    pub via: VerificationMethod,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct AuthyCreateAccountResponse {
+    pub authy_id: AuthyId,
+}
+
 #[derive(Debug, Serialize, PartialEq)]
 pub(crate) struct AuthyRegisterDeviceRequest {
     pub(crate) api_key: String,
     pub(crate) locale: String,
-    pub(crate) via: String,
+    pub(crate) via: VerificationMethod,
     pub signature: String,
     pub device_app: String,
     pub(crate) device_name: String,
@@ -35,8 +57,8 @@ pub(crate) struct AuthyRegisterDeviceRequest {
 pub(crate) struct AuthyRegisterDeviceResponse {
     message: String,
     pub request_id: String,
-    approval_pin: usize,
-    provider: String,
+    pub approval_pin: Option<usize>,
+    pub provider: String,
     success: bool,
 }
 
@@ -45,7 +67,7 @@ impl Default for AuthyRegisterDeviceRequest {
         Self {
             api_key: API_KEY.to_string(),
             locale: DEFAULT_LOCALE.to_string(),
-            via: "push".to_string(),
+            via: VerificationMethod::Push,
             signature: "".to_string(),
             device_app: DEVICE_APP_NAME.to_string(),
             device_name: "".to_string(),
@@ -98,7 +120,7 @@ pub(crate) struct AuthyCheckCurrentDeviceRequest {
 
 #[derive(Debug, Deserialize)]
 pub(crate) struct AuthyListDevicesResponse {
-    devices: Vec<AuthyDevice>,
+    pub devices: Vec<AuthyDevice>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -115,11 +137,31 @@ pub(crate) struct AuthyDevice {
     registered: bool,
 }
 
+impl From<AuthyDevice> for DeviceInfo {
+    fn from(device: AuthyDevice) -> Self {
+        Self {
+            id: device._id,
+            name: device.name,
+            app: device.device_app,
+            device_type: device.device_type,
+            city: device.city,
+            country: device.country,
+            created_at: device.created_at,
+            registered: device.registered,
+        }
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub(crate) struct AuthyListAuthenticatorTokensReponse {
     pub authenticator_tokens: Vec<AuthenticatorToken>,
 }
 
+#[derive(Debug, Deserialize)]
+pub(crate) struct AuthyListAuthenticatorAppsResponse {
+    pub apps: Vec<AuthenticatorApp>,
+}
+
 #[derive(Debug, Deserialize)]
 pub(crate) struct AuthyCheckDeviceTokensReponse {
     cellphone: String,
@@ -127,7 +169,7 @@ pub(crate) struct AuthyCheckDeviceTokensReponse {
     success: bool,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Serialize)]
 pub(crate) struct AuthyAuthenticatedQueryString {
     api_key: String,
     device_id: String,
@@ -137,15 +179,29 @@ pub(crate) struct AuthyAuthenticatedQueryString {
 }
 
 impl AuthyAuthenticatedQueryString {
-    pub(crate) fn with_device(device: &Device, time_sync: Option<&TimeSync>) -> Self {
-        let (otp1, otp2, otp3) = device.calculate_tokens(time_sync);
-        Self {
+    pub(crate) fn with_device(device: &Device, time_sync: Option<&TimeSync>) -> Result<Self> {
+        let (otp1, otp2, otp3) = device.calculate_tokens(time_sync)?;
+        Ok(Self {
             api_key: API_KEY.to_string(),
             device_id: device.id.to_string(),
             otp1,
             otp2,
             otp3,
-        }
+        })
+    }
+}
+
+/// The codes authenticate the device, they are kept out of logs together
+/// with the device they belong to.
+impl fmt::Debug for AuthyAuthenticatedQueryString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuthyAuthenticatedQueryString")
+            .field("api_key", &self.api_key)
+            .field("device_id", &REDACTED)
+            .field("otp1", &REDACTED)
+            .field("otp2", &REDACTED)
+            .field("otp3", &REDACTED)
+            .finish()
     }
 }
 
@@ -162,25 +218,133 @@ impl AuthyAuthenticatorTokensQueryString {
         apps: &[String],
         device: &Device,
         time_sync: Option<&TimeSync>,
-    ) -> Self {
-        Self {
+    ) -> Result<Self> {
+        Ok(Self {
             apps: apps.join(","),
             locale: DEFAULT_LOCALE.to_string(),
-            authentication: AuthyAuthenticatedQueryString::with_device(device, time_sync),
+            authentication: AuthyAuthenticatedQueryString::with_device(device, time_sync)?,
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct AuthySyncAppsRequest {
+    locale: String,
+    #[serde(flatten)]
+    authentication: AuthyAuthenticatedQueryString,
+}
+
+impl AuthySyncAppsRequest {
+    pub fn with_device(device: &Device, time_sync: Option<&TimeSync>) -> Result<Self> {
+        Ok(Self {
+            locale: DEFAULT_LOCALE.to_string(),
+            authentication: AuthyAuthenticatedQueryString::with_device(device, time_sync)?,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct AuthyListApprovalRequestsResponse {
+    pub approval_requests: Vec<AuthyApprovalRequest>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct AuthyGetApprovalRequestResponse {
+    pub approval_request: AuthyApprovalRequest,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct AuthyApprovalRequest {
+    uuid: String,
+    status: ApprovalStatus,
+    customer_name: String,
+    message: String,
+    #[serde(default)]
+    details: BTreeMap<String, String>,
+    created_at: u64,
+    expires_at: Option<u64>,
+}
+
+impl From<AuthyApprovalRequest> for ApprovalRequest {
+    fn from(request: AuthyApprovalRequest) -> Self {
+        Self {
+            uuid: request.uuid,
+            status: request.status,
+            app_name: request.customer_name,
+            message: request.message,
+            details: request.details,
+            created_at: request.created_at,
+            expires_at: request.expires_at,
         }
     }
 }
 
+#[derive(Debug, Serialize)]
+pub(crate) struct AuthyApprovalRequestsQueryString {
+    status: ApprovalStatus,
+    locale: String,
+    #[serde(flatten)]
+    authentication: AuthyAuthenticatedQueryString,
+}
+
+impl AuthyApprovalRequestsQueryString {
+    pub fn with_status_and_device(
+        status: ApprovalStatus,
+        device: &Device,
+        time_sync: Option<&TimeSync>,
+    ) -> Result<Self> {
+        Ok(Self {
+            status,
+            locale: DEFAULT_LOCALE.to_string(),
+            authentication: AuthyAuthenticatedQueryString::with_device(device, time_sync)?,
+        })
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub(crate) struct AuthySyncTimeWithServerResponse {
     moving_factor: String,
 }
 
 impl AuthySyncTimeWithServerResponse {
-    pub fn get_moving_factor_in_unix_timestamp(&self) -> u64 {
+    pub fn get_moving_factor_in_unix_timestamp(&self) -> Result<u64> {
         // Right pad with zeroes on the right as the sent value is not really a valid
         // timestamp
         let padded = format!("{:0<10}", self.moving_factor);
-        padded.parse().expect("failed to parse moving factor")
+        padded
+            .parse()
+            .map_err(|_| MambembeError::MissingField("moving_factor"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        api_models::{AuthyApprovalRequestsQueryString, AuthyAuthenticatorTokensQueryString},
+        models::{ApprovalStatus, Device},
+    };
+
+    #[test]
+    fn authentication_is_redacted_from_debug() {
+        let device: Device = serde_json::from_value(serde_json::json!({
+            "id": 12334,
+            "secret_seed": "1bcc2b0a43e94a90916a04079190af40"
+        }))
+        .unwrap();
+        let (otp1, otp2, otp3) = device.calculate_tokens(None).unwrap();
+
+        let query =
+            AuthyAuthenticatorTokensQueryString::with_apps_and_device(&[], &device, None).unwrap();
+        let approvals = AuthyApprovalRequestsQueryString::with_status_and_device(
+            ApprovalStatus::Pending,
+            &device,
+            None,
+        )
+        .unwrap();
+        for debug in &[format!("{:?}", query), format!("{:?}", approvals)] {
+            for secret in &["12334", &otp1, &otp2, &otp3] {
+                assert!(!debug.contains(secret), "{} leaks {}", debug, secret);
+            }
+        }
     }
 }
@@ -1,38 +1,53 @@
-use std::{fmt::Write, fs};
+use std::{
+    fmt::{self, Write},
+    fs, result,
+    sync::OnceLock,
+    time::Duration,
+};
 
 use async_trait::async_trait;
 use rand::{thread_rng, Rng};
-use reqwest::Client;
-use rsa::RsaPrivateKey;
-use serde::{Deserialize, Serialize};
+use reqwest::{header::RETRY_AFTER, Client, RequestBuilder, Response};
+use rsa::{Pkcs1v15Encrypt, Pkcs1v15Sign, RsaPrivateKey};
+use serde::{Deserialize, Serialize, Serializer};
 use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tokio::time::sleep;
 use tracing::{debug, instrument};
 use url::Url;
+use zeroize::Zeroizing;
 
 use crate::{
     api_models::{
-        AuthyAuthenticatedQueryString, AuthyAuthenticatorTokensQueryString,
-        AuthyCheckCurrentDeviceRequest, AuthyCheckDeviceTokensReponse,
-        AuthyCheckRegistrationRequest, AuthyCheckRegistrationResponse,
-        AuthyCheckRegistrationStatus, AuthyCheckStatusResponse, AuthyCompleteRegistrationRequest,
-        AuthyCompleteRegistrationResponse, AuthyListAuthenticatorTokensReponse,
-        AuthyListDevicesResponse, AuthyRegisterDeviceRequest, AuthyRegisterDeviceResponse,
-        AuthySyncTimeWithServerResponse,
+        AuthyApprovalRequestsQueryString, AuthyAuthenticatedQueryString,
+        AuthyAuthenticatorTokensQueryString, AuthyCheckCurrentDeviceRequest,
+        AuthyCheckDeviceTokensReponse, AuthyCheckRegistrationRequest,
+        AuthyCheckRegistrationResponse, AuthyCheckRegistrationStatus, AuthyCheckStatusResponse,
+        AuthyCompleteRegistrationRequest, AuthyCompleteRegistrationResponse,
+        AuthyCreateAccountRequest, AuthyCreateAccountResponse, AuthyGetApprovalRequestResponse,
+        AuthyListApprovalRequestsResponse, AuthyListAuthenticatorAppsResponse,
+        AuthyListAuthenticatorTokensReponse, AuthyListDevicesResponse, AuthyRegisterDeviceRequest,
+        AuthyRegisterDeviceResponse, AuthySyncAppsRequest, AuthySyncTimeWithServerResponse,
     },
+    builder::{AuthyClientBuilder, HttpOptions},
     constants::{API_KEY, DEFAULT_LOCALE, DEVICE_APP_NAME, PRODUCTION_URL},
     error::{MambembeError, Result},
+    generator::{app_otp_token, otp_token, verify_otp_token, TokenGenerator},
+    http_policy::HttpPolicy,
     models::{
-        AuthenticatorToken, CheckRegistrationStatus, CheckStatusResponse, Device,
-        RegisterDeviceResponse,
+        ApprovalRequest, ApprovalStatus, AuthenticatorApp, AuthenticatorToken,
+        CheckRegistrationStatus, CheckStatusResponse, Device, DeviceInfo, OtpCode, PasswordStorage,
+        RegisterDeviceResponse, VerificationMethod,
     },
-    tokens::{calculate_token, get_time},
-    utils::{check_api_errors, client_builder, parse_private_key},
+    secret::{Secret, REDACTED},
+    tokens::{get_time, is_valid_seed, AUTHY_DEFAULT_PERIOD},
+    utils::{check_api_errors, client_builder, parse_private_key, parse_response},
 };
 
 pub(crate) type AuthyId = u64;
 
-#[derive(Debug, Deserialize, Serialize)]
-pub(crate) enum TimeSync {
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub enum TimeSync {
     Future {
         last_time_checked: u64,
         time_offset: u64,
@@ -44,6 +59,53 @@ pub(crate) enum TimeSync {
 }
 
 impl TimeSync {
+    /// `moving_factor` is authy's time rounded down to its 10 seconds step,
+    /// so a local time within that step is as close as it can be measured.
+    pub(crate) fn measure(local_time: u64, moving_factor: u64) -> Self {
+        if local_time >= moving_factor && local_time - moving_factor < AUTHY_DEFAULT_PERIOD {
+            TimeSync::Future {
+                last_time_checked: local_time,
+                time_offset: 0,
+            }
+        } else if moving_factor < local_time {
+            TimeSync::Past {
+                last_time_checked: local_time,
+                time_offset: local_time - moving_factor,
+            }
+        } else {
+            TimeSync::Future {
+                last_time_checked: local_time,
+                time_offset: moving_factor - local_time,
+            }
+        }
+    }
+
+    /// Local unix time of the last sync.
+    pub fn last_time_checked(&self) -> u64 {
+        match self {
+            TimeSync::Future {
+                last_time_checked, ..
+            }
+            | TimeSync::Past {
+                last_time_checked, ..
+            } => *last_time_checked,
+        }
+    }
+
+    /// Seconds authy's clock is ahead of the local one, negative when it is
+    /// behind.
+    pub fn offset(&self) -> i64 {
+        match self {
+            TimeSync::Future { time_offset, .. } => *time_offset as i64,
+            TimeSync::Past { time_offset, .. } => -(*time_offset as i64),
+        }
+    }
+
+    pub fn is_older_than(&self, max_age: Duration) -> bool {
+        let age = get_time(None).saturating_sub(self.last_time_checked());
+        Duration::from_secs(age) > max_age
+    }
+
     pub(crate) fn correct_time(&self, time: u64) -> u64 {
         match self {
             TimeSync::Future {
@@ -61,23 +123,51 @@ impl TimeSync {
 #[async_trait]
 pub trait AuthyClientApi {
     async fn check_user_status(&mut self, phone: &str) -> Result<CheckStatusResponse>;
-    async fn register_device(&self) -> Result<RegisterDeviceResponse>;
+    async fn create_account(
+        &mut self,
+        email: &str,
+        country_code: &str,
+        cellphone: &str,
+        via: VerificationMethod,
+    ) -> Result<()>;
+    async fn register_device(&self, via: VerificationMethod) -> Result<RegisterDeviceResponse>;
     async fn check_registration(&self, request_id: &str) -> Result<CheckRegistrationStatus>;
     async fn complete_registration(&mut self, pin: &str) -> Result<()>;
     async fn check_current_device(&self) -> Result<()>;
     async fn check_current_device_keys(&self) -> Result<()>;
     async fn fetch_private_keys(&mut self) -> Result<()>;
-    async fn list_devices(&self) -> Result<Vec<Device>>;
+    async fn list_devices(&self) -> Result<Vec<DeviceInfo>>;
+    async fn delete_device(&self, device_id: u64) -> Result<()>;
+    async fn unregister_device(&mut self) -> Result<()>;
     async fn sync_time_with_server(&mut self) -> Result<()>;
+    async fn sync_time_if_older_than(&mut self, max_age: Duration) -> Result<bool>;
     async fn list_authenticator_tokens(&self) -> Result<Vec<AuthenticatorToken>>;
-    async fn get_otp_token(&self, authentication_token: &AuthenticatorToken) -> Result<String>;
+    async fn verify_backup_password(&self) -> Result<()>;
+    async fn change_backup_password(
+        &mut self,
+        backup_password: &str,
+    ) -> Result<Vec<AuthenticatorToken>>;
+    async fn get_otp_token(&self, authentication_token: &AuthenticatorToken) -> Result<OtpCode>;
+    async fn list_authenticator_apps(&self) -> Result<Vec<AuthenticatorApp>>;
+    async fn get_app_otp_token(&self, authenticator_app: &AuthenticatorApp) -> Result<OtpCode>;
+    async fn list_approval_requests(&self) -> Result<Vec<ApprovalRequest>>;
+    async fn get_approval_request(&self, uuid: &str) -> Result<ApprovalRequest>;
+    fn verify_otp(
+        &self,
+        authentication_token: &AuthenticatorToken,
+        code: &str,
+        window: u32,
+    ) -> Result<Option<i64>>;
     fn initialize_authenticator_token(
         &self,
         authentication_token: &mut AuthenticatorToken,
     ) -> Result<()>;
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Serialization leaves the backup password out unless its storage is
+/// [`PasswordStorage::Inline`], it then has to be given with
+/// [`AuthyClient::set_backup_password`] after loading the client.
+#[derive(Debug, Deserialize)]
 pub struct AuthyClient {
     url: Url,
     pub device_name: String,
@@ -86,12 +176,69 @@ pub struct AuthyClient {
     request_id: Option<String>,
     device: Option<Device>,
     time_sync: Option<TimeSync>,
-    private_key: Option<String>,
-    backup_password: String,
+    private_key: Option<Secret<String>>,
+    #[serde(default)]
+    backup_password: Secret<String>,
+    #[serde(default)]
+    password_storage: PasswordStorage,
+    #[serde(skip)]
+    parsed_private_key: Option<DeviceKey>,
     #[serde(skip)]
-    parsed_private_key: Option<RsaPrivateKey>,
+    http_options: HttpOptions,
     #[serde(skip)]
-    http_client: Client,
+    offline: bool,
+    /// Built on first use, so loading a saved client can't fail because of it.
+    #[serde(skip)]
+    http_client: OnceLock<Client>,
+}
+
+/// `RsaPrivateKey` already wipes itself when dropped, this only keeps it out
+/// of `Debug`.
+struct DeviceKey(RsaPrivateKey);
+
+impl fmt::Debug for DeviceKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+#[derive(Serialize)]
+struct AuthyClientRecord<'a> {
+    url: &'a Url,
+    device_name: &'a str,
+    signature: &'a str,
+    authy_id: Option<AuthyId>,
+    request_id: Option<&'a str>,
+    device: Option<&'a Device>,
+    time_sync: Option<&'a TimeSync>,
+    private_key: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    backup_password: Option<&'a str>,
+    password_storage: PasswordStorage,
+}
+
+impl Serialize for AuthyClient {
+    fn serialize<S>(&self, serializer: S) -> result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        AuthyClientRecord {
+            url: &self.url,
+            device_name: &self.device_name,
+            signature: &self.signature,
+            authy_id: self.authy_id,
+            request_id: self.request_id.as_deref(),
+            device: self.device.as_ref(),
+            time_sync: self.time_sync.as_ref(),
+            private_key: self.private_key.as_ref().map(|key| key.expose().as_str()),
+            backup_password: match self.password_storage {
+                PasswordStorage::Inline => Some(self.backup_password.expose()),
+                PasswordStorage::Keyring | PasswordStorage::Prompt => None,
+            },
+            password_storage: self.password_storage,
+        }
+        .serialize(serializer)
+    }
 }
 
 impl AuthyClient {
@@ -104,8 +251,21 @@ impl AuthyClient {
             .map_err(MambembeError::ConfigParsingError)
     }
 
-    #[instrument]
+    pub fn builder() -> AuthyClientBuilder {
+        AuthyClientBuilder::new()
+    }
+
+    #[instrument(skip(backup_password))]
     pub fn with_url(url: &str, device_name: &str, backup_password: &str) -> Result<Self> {
+        Self::from_parts(url, device_name, backup_password, HttpOptions::default())
+    }
+
+    pub(crate) fn from_parts(
+        url: &str,
+        device_name: &str,
+        backup_password: &str,
+        http_options: HttpOptions,
+    ) -> Result<Self> {
         let mut signature = [0u8; 32];
         thread_rng().fill(&mut signature);
         let signature = signature.iter().fold(String::new(), |mut output, n| {
@@ -117,25 +277,150 @@ impl AuthyClient {
             url: url.parse()?,
             device_name: device_name.to_string(),
             signature,
-            backup_password: backup_password.to_string(),
+            backup_password: backup_password.to_string().into(),
+            password_storage: PasswordStorage::default(),
             authy_id: None,
             request_id: None,
             device: None,
             time_sync: None,
             private_key: None,
             parsed_private_key: None,
-            http_client: client_builder(),
+            http_client: OnceLock::from(client_builder(&http_options)?),
+            http_options,
+            offline: false,
         })
     }
 
-    /// Not used right now but maybe in the future
-    #[allow(dead_code)]
+    /// Replaces the timeouts and retries used when talking to authy.
+    pub fn with_http_policy(mut self, http_policy: HttpPolicy) -> Result<Self> {
+        let http_options = HttpOptions {
+            policy: http_policy,
+            ..self.http_options.clone()
+        };
+        self.set_http_options(http_options)?;
+        Ok(self)
+    }
+
+    /// When offline every call that needs authy's api fails with
+    /// [`MambembeError::OfflineMode`] instead of touching the network.
+    pub fn set_offline(&mut self, offline: bool) {
+        self.offline = offline;
+    }
+
+    pub fn is_offline(&self) -> bool {
+        self.offline
+    }
+
+    pub fn device_id(&self) -> Option<u64> {
+        self.device.as_ref().map(|device| device.id)
+    }
+
+    /// Replaces the backup password, e.g. after
+    /// [`AuthyClientApi::verify_backup_password`] rejected it.
+    pub fn set_backup_password(&mut self, backup_password: &str) {
+        self.backup_password = backup_password.to_string().into();
+    }
+
+    pub fn password_storage(&self) -> PasswordStorage {
+        self.password_storage
+    }
+
+    pub fn set_password_storage(&mut self, password_storage: PasswordStorage) {
+        self.password_storage = password_storage;
+    }
+
+    pub fn time_sync(&self) -> Option<&TimeSync> {
+        self.time_sync.as_ref()
+    }
+
+    pub fn set_time_sync(&mut self, time_sync: Option<TimeSync>) {
+        self.time_sync = time_sync;
+    }
+
+    pub fn token_generator(&self) -> TokenGenerator {
+        TokenGenerator::new(self.backup_password.expose(), self.time_sync.clone())
+    }
+
+    pub(crate) fn set_url(&mut self, url: &str) -> Result<()> {
+        self.url = url.parse()?;
+        Ok(())
+    }
+
+    pub(crate) fn set_http_options(&mut self, http_options: HttpOptions) -> Result<()> {
+        self.http_client = OnceLock::from(client_builder(&http_options)?);
+        self.http_options = http_options;
+        Ok(())
+    }
+
+    /// Decrypts data encrypted to this device with PKCS#1 v1.5, e.g. the
+    /// payload of approval requests. Needs
+    /// [`AuthyClientApi::fetch_private_keys`].
+    pub fn decrypt_with_device_key(&self, data: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+        let key = self.get_private_key()?;
+        Ok(Zeroizing::new(key.decrypt(Pkcs1v15Encrypt, data)?))
+    }
+
+    /// Signs `message` with PKCS#1 v1.5 and SHA-256 using the device key.
+    /// Needs [`AuthyClientApi::fetch_private_keys`].
+    pub fn sign_with_device_key(&self, message: &[u8]) -> Result<Vec<u8>> {
+        let key = self.get_private_key()?;
+        let digest = Sha256::digest(message);
+        Ok(key.sign(Pkcs1v15Sign::new::<Sha256>(), &digest)?)
+    }
+
     fn get_private_key(&self) -> Result<&RsaPrivateKey> {
         self.parsed_private_key
             .as_ref()
+            .map(|key| &key.0)
             .ok_or(MambembeError::PrivateKeyNotFetched)
     }
 
+    async fn send(&self, request: RequestBuilder) -> Result<Response> {
+        if self.offline {
+            return Err(MambembeError::OfflineMode);
+        }
+        let request = request.build().map_err(MambembeError::TransportError)?;
+        let policy = &self.http_options.policy;
+        let mut attempt = 0;
+        loop {
+            // Requests with streaming bodies can't be retried, none are used for now
+            let current = match request.try_clone() {
+                Some(current) => current,
+                None => break,
+            };
+            let delay = match self.http_client()?.execute(current).await {
+                Ok(response)
+                    if policy.should_retry_status(attempt, request.method(), response.status()) =>
+                {
+                    policy.retry_delay(attempt, response.headers().get(RETRY_AFTER))
+                }
+                Ok(response) => return check_api_errors(response).await,
+                Err(err) if policy.should_retry_error(attempt, request.method(), &err) => {
+                    policy.backoff(attempt)
+                }
+                Err(err) => return Err(MambembeError::TransportError(err)),
+            };
+            attempt += 1;
+            debug!("Retrying request in {:?}, attempt {}", delay, attempt);
+            sleep(delay).await;
+        }
+
+        let response = self
+            .http_client()?
+            .execute(request)
+            .await
+            .map_err(MambembeError::TransportError)?;
+        check_api_errors(response).await
+    }
+
+    fn http_client(&self) -> Result<&Client> {
+        if let Some(http_client) = self.http_client.get() {
+            return Ok(http_client);
+        }
+        let http_client = client_builder(&self.http_options)?;
+        Ok(self.http_client.get_or_init(|| http_client))
+    }
+
     fn get_authy_id(&self) -> Result<AuthyId> {
         self.authy_id.ok_or(MambembeError::DeviceNotInitialized)
     }
@@ -152,55 +437,89 @@ impl AuthyClientApi for AuthyClient {
     #[instrument]
     async fn check_user_status(&mut self, phone: &str) -> Result<CheckStatusResponse> {
         let response = self
-            .http_client
-            .get(&format!("{}/users/{}/status", self.url.as_str(), phone))
-            .query(&[
-                ("api_key", API_KEY),
-                ("uuid", "123"),
-                ("locale", DEFAULT_LOCALE),
-            ])
-            .send()
-            .await
-            .unwrap();
-
-        let response = check_api_errors(response).await?;
+            .send(
+                self.http_client()?
+                    .get(&format!("{}/users/{}/status", self.url.as_str(), phone))
+                    .query(&[
+                        ("api_key", API_KEY),
+                        ("uuid", "123"),
+                        ("locale", DEFAULT_LOCALE),
+                    ]),
+            )
+            .await?;
 
-        let authy_response: AuthyCheckStatusResponse = response.json().await.unwrap();
+        let authy_response: AuthyCheckStatusResponse = parse_response(response).await?;
         self.authy_id = authy_response.authy_id;
 
         let output = match authy_response.message.as_str() {
             "new" => CheckStatusResponse::RegisterAccount,
             "active" => CheckStatusResponse::RegisterDevice,
-            e => panic!("implement {}", e),
+            other => return Err(MambembeError::UnknownStatusMessage(other.to_string())),
         };
         Ok(output)
     }
 
+    /// Creates a new authy account for the phone, authy then sends a
+    /// verification code via `via` which has to be used as the pin on
+    /// `complete_registration`.
+    #[instrument]
+    async fn create_account(
+        &mut self,
+        email: &str,
+        country_code: &str,
+        cellphone: &str,
+        via: VerificationMethod,
+    ) -> Result<()> {
+        // There is no other device to approve a push for a brand new account
+        if via == VerificationMethod::Push {
+            return Err(MambembeError::InvalidVerificationMethod(via.to_string()));
+        }
+        let payload = AuthyCreateAccountRequest {
+            api_key: API_KEY.to_string(),
+            locale: DEFAULT_LOCALE.to_string(),
+            email: email.to_string(),
+            cellphone: cellphone.to_string(),
+            country_code: country_code.to_string(),
+            via,
+        };
+
+        let url = format!("{}/users/new", self.url);
+        let response = self
+            .send(self.http_client()?.post(&url).form(&payload))
+            .await?;
+        let data: AuthyCreateAccountResponse = parse_response(response).await?;
+        self.authy_id = Some(data.authy_id);
+        Ok(())
+    }
+
     #[instrument]
-    async fn register_device(&self) -> Result<RegisterDeviceResponse> {
+    async fn register_device(&self, via: VerificationMethod) -> Result<RegisterDeviceResponse> {
         let authy_id = self.get_authy_id()?;
         let payload = AuthyRegisterDeviceRequest {
             device_name: self.device_name.clone(),
             signature: self.signature.clone(),
+            via,
             ..Default::default()
         };
 
         let response = self
-            .http_client
-            .post(&format!(
-                "{}/users/{}/devices/registration/start",
-                self.url.as_str(),
-                authy_id
-            ))
-            .form(&payload)
-            .send()
-            .await
-            .unwrap();
-
-        let response = check_api_errors(response).await?;
+            .send(
+                self.http_client()?
+                    .post(&format!(
+                        "{}/users/{}/devices/registration/start",
+                        self.url.as_str(),
+                        authy_id
+                    ))
+                    .form(&payload),
+            )
+            .await?;
 
-        let data: AuthyRegisterDeviceResponse = response.json().await.unwrap();
-        Ok(RegisterDeviceResponse::RegistrationPending(data.request_id))
+        let data: AuthyRegisterDeviceResponse = parse_response(response).await?;
+        Ok(RegisterDeviceResponse::RegistrationPending {
+            request_id: data.request_id,
+            approval_pin: data.approval_pin,
+            provider: data.provider,
+        })
     }
 
     #[instrument]
@@ -212,28 +531,27 @@ impl AuthyClientApi for AuthyClient {
         };
 
         let response = self
-            .http_client
-            .get(&format!(
-                "{}/users/{}/devices/registration/{}/status",
-                self.url,
-                self.get_authy_id()?,
-                request_id
-            ))
-            .query(&payload)
-            .send()
-            .await
-            .unwrap();
-        let response = check_api_errors(response).await?;
-        let data: AuthyCheckRegistrationResponse = response.json().await.unwrap();
+            .send(
+                self.http_client()?
+                    .get(&format!(
+                        "{}/users/{}/devices/registration/{}/status",
+                        self.url,
+                        self.get_authy_id()?,
+                        request_id
+                    ))
+                    .query(&payload),
+            )
+            .await?;
+        let data: AuthyCheckRegistrationResponse = parse_response(response).await?;
         Ok(match data.status {
             AuthyCheckRegistrationStatus::Pending => CheckRegistrationStatus::Pending,
             AuthyCheckRegistrationStatus::Accepted => CheckRegistrationStatus::Accepted(
-                data.pin.expect("pin not sent when it should have"),
+                data.pin.ok_or(MambembeError::MissingField("pin"))?,
             ),
         })
     }
 
-    #[instrument]
+    #[instrument(skip(pin))]
     async fn complete_registration(&mut self, pin: &str) -> Result<()> {
         // I'm assuming this is used for idempotency so this should suffice
         let uuid = format!("{:x}", md5::compute(pin.as_bytes()));
@@ -246,19 +564,17 @@ impl AuthyClientApi for AuthyClient {
             uuid,
         };
         let response = self
-            .http_client
-            .post(&format!(
-                "{}/users/{}/devices/registration/complete",
-                self.url,
-                self.get_authy_id()?
-            ))
-            .form(&payload)
-            .send()
-            .await
-            .unwrap();
-
-        let response = check_api_errors(response).await?;
-        let data: AuthyCompleteRegistrationResponse = response.json().await.unwrap();
+            .send(
+                self.http_client()?
+                    .post(&format!(
+                        "{}/users/{}/devices/registration/complete",
+                        self.url,
+                        self.get_authy_id()?
+                    ))
+                    .form(&payload),
+            )
+            .await?;
+        let data: AuthyCompleteRegistrationResponse = parse_response(response).await?;
 
         self.device = data.device;
         Ok(())
@@ -277,18 +593,15 @@ impl AuthyClientApi for AuthyClient {
             sha: device.hash_secret(),
         };
 
-        let response = self
-            .http_client
-            .get(&format!(
-                "{}/devices/{1}/soft_tokens/{1}/check",
-                self.url, device.id
-            ))
-            .query(&payload)
-            .send()
-            .await
-            .unwrap();
-
-        check_api_errors(response).await?;
+        self.send(
+            self.http_client()?
+                .get(&format!(
+                    "{}/devices/{1}/soft_tokens/{1}/check",
+                    self.url, device.id
+                ))
+                .query(&payload),
+        )
+        .await?;
 
         Ok(())
     }
@@ -296,7 +609,7 @@ impl AuthyClientApi for AuthyClient {
     #[instrument]
     async fn check_current_device_keys(&self) -> Result<()> {
         let device = self.get_device()?;
-        let (otp1, otp2, otp3) = device.calculate_tokens(self.time_sync.as_ref());
+        let (otp1, otp2, otp3) = device.calculate_tokens(self.time_sync.as_ref())?;
         let url = format!(
             "{}/users/{}/devices/{}",
             self.url,
@@ -305,20 +618,14 @@ impl AuthyClientApi for AuthyClient {
         );
 
         let response = self
-            .http_client
-            .get(&url)
-            .query(&[
+            .send(self.http_client()?.get(&url).query(&[
                 ("api_key", API_KEY),
                 ("otp1", &otp1),
                 ("otp2", &otp2),
                 ("otp3", &otp3),
-            ])
-            .send()
-            .await
-            .unwrap();
-
-        let response = check_api_errors(response).await?;
-        let _data: AuthyCheckDeviceTokensReponse = response.json().await.unwrap();
+            ]))
+            .await?;
+        let _data: AuthyCheckDeviceTokensReponse = parse_response(response).await?;
         Ok(())
     }
 
@@ -326,87 +633,105 @@ impl AuthyClientApi for AuthyClient {
     async fn fetch_private_keys(&mut self) -> Result<()> {
         if let Some(key) = self.private_key.as_ref() {
             if self.parsed_private_key.is_none() {
-                self.parsed_private_key = Some(parse_private_key(key)?);
+                self.parsed_private_key = Some(DeviceKey(parse_private_key(key.expose())?));
             }
             return Ok(());
         }
         let device = self.get_device()?;
         let url = format!("{}/devices/{}/rsa_key", self.url, device.id);
-        let response = self
-            .http_client
-            .get(&url)
-            .query(&AuthyAuthenticatedQueryString::with_device(
-                device,
-                self.time_sync.as_ref(),
+        let response =
+            self.send(self.http_client()?.get(&url).query(
+                &AuthyAuthenticatedQueryString::with_device(device, self.time_sync.as_ref())?,
             ))
-            .send()
-            .await
-            .unwrap();
-        let response = check_api_errors(response).await?;
+            .await?;
 
-        let data = response.json::<Value>().await.unwrap();
-        debug!("Returned private keys {:?}", data);
+        let data: Value = parse_response(response).await?;
         let key = data
             .as_object()
             .and_then(|o| o.get("private_key"))
             .and_then(|k| k.as_str())
-            .expect("failed to get rsa_key");
+            .ok_or(MambembeError::MissingField("private_key"))?;
 
-        self.parsed_private_key = Some(parse_private_key(key)?);
-        self.private_key = Some(key.to_string());
+        self.parsed_private_key = Some(DeviceKey(parse_private_key(key)?));
+        self.private_key = Some(key.to_string().into());
         Ok(())
     }
 
     #[instrument]
-    async fn list_devices(&self) -> Result<Vec<Device>> {
-        let url = format!("{}/json/users/{}/devices", self.url, self.get_authy_id()?);
+    async fn list_devices(&self) -> Result<Vec<DeviceInfo>> {
+        let url = format!("{}/users/{}/devices", self.url, self.get_authy_id()?);
         let response = self
-            .http_client
-            .get(&url)
-            .query(&[("api_key", API_KEY)])
-            .send()
-            .await
-            .unwrap();
-        let response = check_api_errors(response).await?;
-        let data: AuthyListDevicesResponse = response.json().await.unwrap();
-        todo!("{:?}", data);
+            .send(self.http_client()?.get(&url).query(&[("api_key", API_KEY)]))
+            .await?;
+        let data: AuthyListDevicesResponse = parse_response(response).await?;
+        Ok(data.devices.into_iter().map(DeviceInfo::from).collect())
+    }
+
+    /// Deletes a device by its numeric id, the one kept in `Device::id`. The
+    /// hex ids of the devices listing are not accepted by this endpoint.
+    #[instrument]
+    async fn delete_device(&self, device_id: u64) -> Result<()> {
+        let device = self.get_device()?;
+        let url = format!(
+            "{}/users/{}/devices/{}/delete",
+            self.url,
+            self.get_authy_id()?,
+            device_id
+        );
+        self.send(self.http_client()?.post(&url).form(
+            &AuthyAuthenticatedQueryString::with_device(device, self.time_sync.as_ref())?,
+        ))
+        .await?;
+        Ok(())
+    }
+
+    /// Deletes the device registered by this client and forgets everything
+    /// related to it, so the client can't be used anymore without registering
+    /// again.
+    #[instrument]
+    async fn unregister_device(&mut self) -> Result<()> {
+        let device_id = self.get_device()?.id;
+        self.delete_device(device_id).await?;
+
+        self.device = None;
+        self.private_key = None;
+        self.parsed_private_key = None;
+        Ok(())
     }
 
     #[instrument(skip(self))]
     async fn sync_time_with_server(&mut self) -> Result<()> {
         let device = self.get_device()?;
         let url = format!("{}/devices/{}/auth_sync", self.url, device.id);
-        let time = get_time(self.time_sync.as_ref());
-        let response = self
-            .http_client
-            .get(&url)
-            .query(&AuthyAuthenticatedQueryString::with_device(
-                device,
-                self.time_sync.as_ref(),
+        // Measured against the local clock, the current offset is only used to
+        // authenticate the request
+        let time = get_time(None);
+        let response =
+            self.send(self.http_client()?.get(&url).query(
+                &AuthyAuthenticatedQueryString::with_device(device, self.time_sync.as_ref())?,
             ))
-            .send()
-            .await
-            .unwrap();
-
-        let response = check_api_errors(response).await?;
-        let data: AuthySyncTimeWithServerResponse = response.json().await.unwrap();
-        let moving_factor = data.get_moving_factor_in_unix_timestamp();
-        let time_sync = if moving_factor < time {
-            TimeSync::Past {
-                last_time_checked: time,
-                time_offset: time - moving_factor,
-            }
-        } else {
-            TimeSync::Future {
-                last_time_checked: time,
-                time_offset: moving_factor - time,
-            }
-        };
-        self.time_sync = Some(time_sync);
+            .await?;
+        let data: AuthySyncTimeWithServerResponse = parse_response(response).await?;
+        let moving_factor = data.get_moving_factor_in_unix_timestamp()?;
+        self.time_sync = Some(TimeSync::measure(time, moving_factor));
 
         Ok(())
     }
 
+    /// Syncs when it never did or the last sync is older than `max_age`,
+    /// returns whether it did.
+    #[instrument(skip(self))]
+    async fn sync_time_if_older_than(&mut self, max_age: Duration) -> Result<bool> {
+        let stale = self
+            .time_sync
+            .as_ref()
+            .map_or(true, |time_sync| time_sync.is_older_than(max_age));
+        if stale {
+            self.sync_time_with_server().await?;
+        }
+        Ok(stale)
+    }
+
     #[instrument]
     async fn list_authenticator_tokens(&self) -> Result<Vec<AuthenticatorToken>> {
         let device = self.get_device()?;
@@ -417,29 +742,139 @@ impl AuthyClientApi for AuthyClient {
             self.get_authy_id()?
         );
         let response = self
-            .http_client
-            .get(&url)
-            .query(&AuthyAuthenticatorTokensQueryString::with_apps_and_device(
-                &[],
-                device,
-                self.time_sync.as_ref(),
+            .send(self.http_client()?.get(&url).query(
+                &AuthyAuthenticatorTokensQueryString::with_apps_and_device(
+                    &[],
+                    device,
+                    self.time_sync.as_ref(),
+                )?,
             ))
-            .send()
-            .await
-            .unwrap();
-        let response = check_api_errors(response).await?;
-        let data: AuthyListAuthenticatorTokensReponse = response.json().await.unwrap();
+            .await?;
+        let data: AuthyListAuthenticatorTokensReponse = parse_response(response).await?;
         Ok(data.authenticator_tokens)
     }
 
+    /// Fetches the tokens and checks that the backup password decrypts at
+    /// least one seed.
+    #[instrument]
+    async fn verify_backup_password(&self) -> Result<()> {
+        let mut tokens = self.list_authenticator_tokens().await?;
+        for token in &mut tokens {
+            token.initialize_token(self.backup_password.expose());
+        }
+        check_backup_password(&tokens)
+    }
+
+    /// Only replaces the password when it decrypts the current tokens, which
+    /// are returned with their keys derived from it so any cache can be
+    /// replaced.
+    #[instrument(skip(self, backup_password))]
+    async fn change_backup_password(
+        &mut self,
+        backup_password: &str,
+    ) -> Result<Vec<AuthenticatorToken>> {
+        let mut tokens = self.list_authenticator_tokens().await?;
+        for token in &mut tokens {
+            token.reset_token(backup_password);
+        }
+        check_backup_password(&tokens)?;
+        self.backup_password = backup_password.to_string().into();
+        Ok(tokens)
+    }
+
     #[instrument(skip(self, authentication_token), fields(token_name = authentication_token.name.as_str()))]
-    async fn get_otp_token(&self, authentication_token: &AuthenticatorToken) -> Result<String> {
-        let seed = authentication_token.decrypt_seed()?;
-        calculate_token(&seed, authentication_token.digits, self.time_sync.as_ref()).map_err(
-            |source| MambembeError::FailedToCalculateToken {
-                service_name: authentication_token.name.clone(),
-                source,
-            },
+    async fn get_otp_token(&self, authentication_token: &AuthenticatorToken) -> Result<OtpCode> {
+        otp_token(authentication_token, get_time(self.time_sync.as_ref()))
+    }
+
+    #[instrument]
+    async fn list_authenticator_apps(&self) -> Result<Vec<AuthenticatorApp>> {
+        let device = self.get_device()?;
+
+        let url = format!(
+            "{}/users/{}/devices/{}/apps/sync",
+            self.url,
+            self.get_authy_id()?,
+            device.id
+        );
+        let response = self
+            .send(
+                self.http_client()?
+                    .post(&url)
+                    .form(&AuthySyncAppsRequest::with_device(
+                        device,
+                        self.time_sync.as_ref(),
+                    )?),
+            )
+            .await?;
+        let data: AuthyListAuthenticatorAppsResponse = parse_response(response).await?;
+        Ok(data.apps)
+    }
+
+    #[instrument(skip(self, authenticator_app), fields(app_name = authenticator_app.name.as_str()))]
+    async fn get_app_otp_token(&self, authenticator_app: &AuthenticatorApp) -> Result<OtpCode> {
+        app_otp_token(authenticator_app, get_time(self.time_sync.as_ref()))
+    }
+
+    /// Pending requests addressed to this device.
+    #[instrument]
+    async fn list_approval_requests(&self) -> Result<Vec<ApprovalRequest>> {
+        let device = self.get_device()?;
+        let url = format!(
+            "{}/users/{}/devices/{}/approval_requests",
+            self.url,
+            self.get_authy_id()?,
+            device.id
+        );
+        let response = self
+            .send(self.http_client()?.get(&url).query(
+                &AuthyApprovalRequestsQueryString::with_status_and_device(
+                    ApprovalStatus::Pending,
+                    device,
+                    self.time_sync.as_ref(),
+                )?,
+            ))
+            .await?;
+        let data: AuthyListApprovalRequestsResponse = parse_response(response).await?;
+        Ok(data
+            .approval_requests
+            .into_iter()
+            .map(ApprovalRequest::from)
+            .collect())
+    }
+
+    #[instrument]
+    async fn get_approval_request(&self, uuid: &str) -> Result<ApprovalRequest> {
+        let device = self.get_device()?;
+        let url = format!(
+            "{}/users/{}/devices/{}/approval_requests/{}",
+            self.url,
+            self.get_authy_id()?,
+            device.id,
+            uuid
+        );
+        let response =
+            self.send(self.http_client()?.get(&url).query(
+                &AuthyAuthenticatedQueryString::with_device(device, self.time_sync.as_ref())?,
+            ))
+            .await?;
+        let data: AuthyGetApprovalRequestResponse = parse_response(response).await?;
+        Ok(data.approval_request.into())
+    }
+
+    /// The token has to be initialized, see `initialize_authenticator_token`.
+    #[instrument(skip(self, authentication_token, code), fields(token_name = authentication_token.name.as_str()))]
+    fn verify_otp(
+        &self,
+        authentication_token: &AuthenticatorToken,
+        code: &str,
+        window: u32,
+    ) -> Result<Option<i64>> {
+        verify_otp_token(
+            authentication_token,
+            code,
+            window,
+            get_time(self.time_sync.as_ref()),
         )
     }
 
@@ -447,7 +882,214 @@ impl AuthyClientApi for AuthyClient {
         &self,
         authentication_token: &mut AuthenticatorToken,
     ) -> Result<()> {
-        authentication_token.initialize_token(&self.backup_password);
+        authentication_token.initialize_token(self.backup_password.expose());
         Ok(())
     }
 }
+
+/// The tokens have to be initialized, accounts without tokens have nothing
+/// to check.
+fn check_backup_password(tokens: &[AuthenticatorToken]) -> Result<()> {
+    let decrypts = tokens
+        .iter()
+        .any(|token| token.decrypt_seed().is_ok_and(|seed| is_valid_seed(&seed)));
+    if tokens.is_empty() || decrypts {
+        Ok(())
+    } else {
+        Err(MambembeError::InvalidBackupPassword)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use rand::thread_rng;
+    use rsa::{
+        pkcs1::{EncodeRsaPrivateKey, LineEnding},
+        Pkcs1v15Encrypt, Pkcs1v15Sign, RsaPrivateKey, RsaPublicKey,
+    };
+    use serde_json::json;
+    use sha2::{Digest, Sha256};
+    use tracing::Level;
+    use tracing_subscriber::fmt::{format::FmtSpan, MakeWriter};
+
+    use crate::{
+        client::{AuthyClientApi, TimeSync},
+        models::PasswordStorage,
+        tokens::get_time,
+        AuthyClient, MambembeError,
+    };
+
+    #[derive(Clone, Default)]
+    struct Logs(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Logs {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> MakeWriter<'a> for Logs {
+        type Writer = Self;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    #[test]
+    fn backup_password_is_only_saved_inline() {
+        let mut client = AuthyClient::new("device", "password").unwrap();
+        let persisted = serde_json::to_value(&client).unwrap();
+        assert_eq!(persisted["backup_password"], "password");
+
+        client.set_password_storage(PasswordStorage::Keyring);
+        let persisted = serde_json::to_value(&client).unwrap();
+        assert!(persisted.get("backup_password").is_none());
+
+        let loaded: AuthyClient = serde_json::from_value(persisted).unwrap();
+        assert_eq!(loaded.password_storage(), PasswordStorage::Keyring);
+        assert_eq!(loaded.backup_password.expose(), "");
+    }
+
+    #[tokio::test]
+    async fn malformed_saved_client_is_an_error() {
+        let mut client: AuthyClient = serde_json::from_value(json!({
+            "url": "http://localhost:1234/json",
+            "device_name": "device",
+            "signature": "abcde",
+            "authy_id": 1234,
+            "request_id": null,
+            "device": {"id": 1, "secret_seed": "not hex"},
+            "time_sync": null,
+            "private_key": null,
+            "backup_password": "password",
+        }))
+        .unwrap();
+        let result = client.list_authenticator_tokens().await;
+        assert!(matches!(result, Err(MambembeError::InvalidDeviceSeed)));
+        let result = client.sync_time_with_server().await;
+        assert!(matches!(result, Err(MambembeError::InvalidDeviceSeed)));
+    }
+
+    #[tokio::test]
+    async fn secrets_are_not_logged() {
+        let logs = Logs::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(Level::TRACE)
+            .with_span_events(FmtSpan::NEW)
+            .with_ansi(false)
+            .with_writer(logs.clone())
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        AuthyClient::with_url("http://localhost/json", "device", "backup-password").unwrap();
+        let mut client: AuthyClient = serde_json::from_value(json!({
+            "url": "http://localhost/json",
+            "device_name": "device",
+            "signature": "signature",
+            "authy_id": 1234,
+            "device": {"id": 1, "secret_seed": "device-seed"},
+            "private_key": "private-key",
+            "backup_password": "backup-password",
+        }))
+        .unwrap();
+        client.set_offline(true);
+        tracing::debug!(?client, "loaded");
+        client.list_devices().await.unwrap_err();
+        client.complete_registration("pin-1234").await.unwrap_err();
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        assert!(logs.contains("list_devices"));
+        for secret in &["backup-password", "device-seed", "private-key", "pin-1234"] {
+            assert!(!logs.contains(secret), "{} was logged", secret);
+        }
+    }
+
+    #[tokio::test]
+    async fn device_key_decrypts_and_signs() {
+        let key = RsaPrivateKey::new(&mut thread_rng(), 1024).unwrap();
+        let public_key = RsaPublicKey::from(&key);
+        let mut client = AuthyClient::new("device", "password").unwrap();
+        assert!(matches!(
+            client.sign_with_device_key(b"message"),
+            Err(MambembeError::PrivateKeyNotFetched)
+        ));
+
+        // An already stored key is parsed without talking to authy
+        let pem = key.to_pkcs1_pem(LineEnding::LF).unwrap();
+        client.private_key = Some(pem.to_string().into());
+        client.set_offline(true);
+        client.fetch_private_keys().await.unwrap();
+
+        let encrypted = public_key
+            .encrypt(&mut thread_rng(), Pkcs1v15Encrypt, b"payload")
+            .unwrap();
+        let decrypted = client.decrypt_with_device_key(&encrypted).unwrap();
+        assert_eq!(decrypted.as_slice(), b"payload");
+        assert!(matches!(
+            client.decrypt_with_device_key(b"garbage"),
+            Err(MambembeError::DeviceKeyError(_))
+        ));
+
+        let signature = client.sign_with_device_key(b"message").unwrap();
+        public_key
+            .verify(
+                Pkcs1v15Sign::new::<Sha256>(),
+                &Sha256::digest(b"message"),
+                &signature,
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn time_sync_is_measured_in_authy_steps() {
+        let moving_factor = 1_700_000_000;
+        for local_time in moving_factor..moving_factor + 10 {
+            assert_eq!(TimeSync::measure(local_time, moving_factor).offset(), 0);
+        }
+        assert_eq!(
+            TimeSync::measure(moving_factor + 95, moving_factor).offset(),
+            -95
+        );
+        assert_eq!(
+            TimeSync::measure(moving_factor - 1, moving_factor).offset(),
+            1
+        );
+
+        let time_sync = TimeSync::measure(moving_factor + 95, moving_factor);
+        assert_eq!(time_sync.last_time_checked(), moving_factor + 95);
+        assert_eq!(time_sync.correct_time(moving_factor + 95), moving_factor);
+    }
+
+    #[tokio::test]
+    async fn time_sync_is_only_renewed_when_old() {
+        let now = get_time(None);
+        let time_sync = TimeSync::measure(now - 120, now - 60);
+        assert!(time_sync.is_older_than(Duration::from_secs(60)));
+        assert!(!time_sync.is_older_than(Duration::from_secs(600)));
+
+        let mut client = AuthyClient::new("device", "password").unwrap();
+        client.set_time_sync(Some(time_sync));
+        let synced = client
+            .sync_time_if_older_than(Duration::from_secs(600))
+            .await
+            .unwrap();
+        assert!(!synced);
+        // Old enough, so it tries to sync with a device it doesn't have
+        let result = client
+            .sync_time_if_older_than(Duration::from_secs(60))
+            .await;
+        assert!(matches!(result, Err(MambembeError::DeviceNotInitialized)));
+    }
+}
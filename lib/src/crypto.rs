@@ -1,6 +1,7 @@
 use aes::cipher::{block_padding::Pkcs7, BlockDecryptMut, KeyIvInit};
 use data_encoding::Encoding;
 use lazy_static::lazy_static;
+use zeroize::Zeroizing;
 
 use crate::error::{InternalError, InternalResult};
 
@@ -15,19 +16,23 @@ lazy_static! {
     };
 }
 
+/// Tokens without their own IV use an empty one.
+pub(crate) const LEGACY_IV: [u8; 16] = [0u8; 16];
+
 /// This will return copied data so the lib does not know how to handle
 /// decryption.
-pub(crate) fn decrypt_data(key: &[u8], data: &str) -> InternalResult<Vec<u8>> {
-    // IV on authy is always empty
-    let iv = [0u8; 16];
-    let cipher = Aes256Cbc::new(key.into(), &iv.into());
+pub(crate) fn decrypt_data(
+    key: &[u8],
+    iv: &[u8; 16],
+    data: &str,
+) -> InternalResult<Zeroizing<Vec<u8>>> {
+    let cipher = Aes256Cbc::new(key.into(), iv.into());
 
-    let buffer = BASE64
-        .decode(data.as_bytes())
-        .expect("data is not valid base64");
+    let buffer = BASE64.decode(data.as_bytes())?;
 
     cipher
         .decrypt_padded_vec_mut::<Pkcs7>(&buffer)
+        .map(Zeroizing::new)
         .map_err(|_| InternalError::DecryptionError)
 }
 
@@ -36,7 +41,10 @@ mod tests {
     use aes::cipher::{block_padding::Pkcs7, BlockEncryptMut, KeyIvInit};
 
     use super::BASE64;
-    use crate::{crypto::decrypt_data, password::derive_key};
+    use crate::{
+        crypto::{decrypt_data, LEGACY_IV},
+        password::{derive_key, DEFAULT_ITERATIONS},
+    };
 
     type Aes256CbcEncryptor = cbc::Encryptor<aes::Aes256>;
 
@@ -57,22 +65,24 @@ mod tests {
     }
 
     #[cfg(test)]
-    fn encrypt_data(key: &[u8], data: &[u8]) -> String {
-        let iv = [0u8; 16];
-        let cipher = Aes256CbcEncryptor::new(key.into(), &iv.into());
+    fn encrypt_data(key: &[u8], iv: &[u8; 16], data: &[u8]) -> String {
+        let cipher = Aes256CbcEncryptor::new(key.into(), iv.into());
         let encrypted = cipher.encrypt_padded_vec_mut::<Pkcs7>(data);
         BASE64.encode(&encrypted)
     }
 
     #[test]
     fn test_decrypt_data() {
-        let key = derive_key("123456", "salty");
+        let key = derive_key("123456", "salty", DEFAULT_ITERATIONS);
+        let key = key.expose();
         let data_to_encrypt = b"my secret seed01";
-        let encrypted = encrypt_data(&key, data_to_encrypt);
-        let decrypted = decrypt_data(&key, &encrypted).unwrap();
-        assert_eq!(
-            String::from_utf8_lossy(&decrypted),
-            String::from_utf8_lossy(&data_to_encrypt[..])
-        );
+        for iv in &[LEGACY_IV, [7u8; 16]] {
+            let encrypted = encrypt_data(key, iv, data_to_encrypt);
+            let decrypted = decrypt_data(key, iv, &encrypted).unwrap();
+            assert_eq!(
+                String::from_utf8_lossy(&decrypted),
+                String::from_utf8_lossy(&data_to_encrypt[..])
+            );
+        }
     }
 }
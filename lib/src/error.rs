@@ -12,6 +12,8 @@ pub enum MambembeError {
     InvalidUrl(#[from] url::ParseError),
     #[error("device not initialized")]
     DeviceNotInitialized,
+    #[error("device seed is not valid hex")]
+    InvalidDeviceSeed,
     #[error("private key not fetched")]
     PrivateKeyNotFetched,
     #[error("config file not found")]
@@ -20,6 +22,20 @@ pub enum MambembeError {
     ConfigParsingError(#[from] serde_json::Error),
     #[error("damaged token halp")]
     DamagedToken,
+    #[error("invalid http client configuration")]
+    HttpClientConfiguration(#[source] reqwest::Error),
+    #[error("invalid user agent {0:?}")]
+    InvalidUserAgent(String),
+    #[error("offline mode is enabled, refusing to contact authy api")]
+    OfflineMode,
+    #[error("failed to communicate with authy api")]
+    TransportError(#[source] reqwest::Error),
+    #[error("unexpected response from authy api")]
+    UnexpectedResponse(#[source] reqwest::Error),
+    #[error("unknown user status {0:?}")]
+    UnknownStatusMessage(String),
+    #[error("field {0:?} is missing or invalid on authy api response")]
+    MissingField(&'static str),
     #[error("api error {body}")]
     ApiError {
         body: String,
@@ -27,6 +43,8 @@ pub enum MambembeError {
     },
     #[error("private key error")]
     PrivateKeyError(#[from] rsa::pkcs1::Error),
+    #[error("failed to use the device key")]
+    DeviceKeyError(#[from] rsa::Error),
     #[error("token not initialized")]
     AuthenticatorTokenNotInitialized,
     #[error("failed to calculate token for service {service_name:?}")]
@@ -34,6 +52,22 @@ pub enum MambembeError {
         service_name: String,
         source: InternalError,
     },
+    #[error("invalid verification method {0:?}")]
+    InvalidVerificationMethod(String),
+    #[error("invalid password storage {0:?}, it has to be inline, keyring or prompt")]
+    InvalidPasswordStorage(String),
+    #[error("invalid algorithm {0:?}, it has to be sha1, sha256 or sha512")]
+    InvalidAlgorithm(String),
+    #[error("secret of {0:?} is not valid base32")]
+    InvalidSecret(String),
+    #[error("codes have 6 to 8 digits, {0} is not supported")]
+    InvalidDigits(usize),
+    #[error(
+        "codes are valid for {period} seconds, they can't stay valid for {min_validity} seconds"
+    )]
+    MinValidityTooLong { min_validity: u64, period: u64 },
+    #[error("backup password can't decrypt any of the authenticator tokens")]
+    InvalidBackupPassword,
     #[error("failed to decrypt seed for service {service_name:?}")]
     FailedToDecryptSeed {
         service_name: String,
@@ -48,4 +82,8 @@ pub enum InternalError {
     DecodeFailed(#[from] DecodeError),
     #[error("decryption error unpad error")]
     DecryptionError,
+    #[error("token period can't be zero")]
+    InvalidPeriod,
+    #[error("iv has to be 16 bytes long")]
+    InvalidIv,
 }
@@ -0,0 +1,213 @@
+use std::time::Duration;
+
+use tracing::instrument;
+
+use crate::{
+    client::TimeSync,
+    error::{MambembeError, Result},
+    models::{AuthenticatorApp, AuthenticatorToken, OtpCode},
+    secret::Secret,
+    tokens::{
+        calculate_app_token, calculate_steam_token, calculate_token, get_time, verify_steam_token,
+        verify_token,
+    },
+};
+
+/// Generates tokens from data that was already fetched, it never talks to
+/// authy so it works without any network access.
+#[derive(Debug, Clone)]
+pub struct TokenGenerator {
+    backup_password: Secret<String>,
+    time_sync: Option<TimeSync>,
+    min_validity: Duration,
+}
+
+impl TokenGenerator {
+    pub fn new(backup_password: &str, time_sync: Option<TimeSync>) -> Self {
+        Self {
+            backup_password: backup_password.to_string().into(),
+            time_sync,
+            min_validity: Duration::ZERO,
+        }
+    }
+
+    /// Codes valid for less than `min_validity` are replaced by the ones of
+    /// the next window, their `seconds_remaining` counts from now. Tokens
+    /// whose period isn't longer than `min_validity` fail to generate.
+    pub fn with_min_validity(mut self, min_validity: Duration) -> Self {
+        self.min_validity = min_validity;
+        self
+    }
+
+    #[instrument(skip(self, authentication_token), fields(token_name = authentication_token.name.as_str()))]
+    pub fn token(&self, authentication_token: &mut AuthenticatorToken) -> Result<OtpCode> {
+        authentication_token.initialize_token(self.backup_password.expose());
+        let authentication_token = &*authentication_token;
+        self.valid_code(|time| otp_token(authentication_token, time))
+    }
+
+    #[instrument(skip(self, authenticator_app), fields(app_name = authenticator_app.name.as_str()))]
+    pub fn app_token(&self, authenticator_app: &AuthenticatorApp) -> Result<OtpCode> {
+        self.valid_code(|time| app_otp_token(authenticator_app, time))
+    }
+
+    /// Checks `code` against the current step and `window` steps before and
+    /// after it, returning the matched step relative to the current one.
+    #[instrument(skip(self, authentication_token, code), fields(token_name = authentication_token.name.as_str()))]
+    pub fn verify(
+        &self,
+        authentication_token: &mut AuthenticatorToken,
+        code: &str,
+        window: u32,
+    ) -> Result<Option<i64>> {
+        authentication_token.initialize_token(self.backup_password.expose());
+        verify_otp_token(
+            authentication_token,
+            code,
+            window,
+            get_time(self.time_sync.as_ref()),
+        )
+    }
+
+    fn valid_code<F>(&self, generate: F) -> Result<OtpCode>
+    where
+        F: Fn(u64) -> Result<OtpCode>,
+    {
+        let time = get_time(self.time_sync.as_ref());
+        let code = generate(time)?;
+        if code.wait_for(self.min_validity)?.is_none() {
+            return Ok(code);
+        }
+        let mut next = generate(time + code.seconds_remaining)?;
+        next.seconds_remaining += code.seconds_remaining;
+        Ok(next)
+    }
+}
+
+pub(crate) fn otp_token(authentication_token: &AuthenticatorToken, time: u64) -> Result<OtpCode> {
+    let seed = authentication_token.decrypt_seed()?;
+    let code = if authentication_token.is_steam() {
+        calculate_steam_token(&seed, time)
+    } else {
+        calculate_token(
+            &seed,
+            authentication_token.digits,
+            authentication_token.algorithm,
+            authentication_token.period,
+            time,
+        )
+    };
+    code.map_err(|source| MambembeError::FailedToCalculateToken {
+        service_name: authentication_token.name.clone(),
+        source,
+    })
+}
+
+pub(crate) fn verify_otp_token(
+    authentication_token: &AuthenticatorToken,
+    code: &str,
+    window: u32,
+    time: u64,
+) -> Result<Option<i64>> {
+    let seed = authentication_token.decrypt_seed()?;
+    let step = if authentication_token.is_steam() {
+        verify_steam_token(&seed, time, code, window)
+    } else {
+        verify_token(
+            &seed,
+            authentication_token.digits,
+            authentication_token.algorithm,
+            authentication_token.period,
+            time,
+            code,
+            window,
+        )
+    };
+    step.map_err(|source| MambembeError::FailedToCalculateToken {
+        service_name: authentication_token.name.clone(),
+        source,
+    })
+}
+
+pub(crate) fn app_otp_token(authenticator_app: &AuthenticatorApp, time: u64) -> Result<OtpCode> {
+    let seed = authenticator_app.decode_seed()?;
+    calculate_app_token(&seed, authenticator_app.digits, time).map_err(|source| {
+        MambembeError::FailedToCalculateToken {
+            service_name: authenticator_app.name.clone(),
+            source,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use crate::{
+        error::MambembeError,
+        generator::TokenGenerator,
+        models::{AuthenticatorApp, AuthenticatorToken},
+    };
+
+    #[test]
+    fn generates_tokens_without_a_client() {
+        let generator = TokenGenerator::new("123456", None);
+        let mut token: AuthenticatorToken = serde_json::from_value(json!({
+            "account_type": "",
+            "digits": 6,
+            "encrypted_seed": "Y8yn1UMAmLjmCOEOi8FJc6G5zvBzZxaFEPpPgl+QaxE=",
+            "name": "",
+            "original_name": null,
+            "password_timestamp": 0,
+            "salt": "salty",
+            "unique_id": "",
+            "derived_key": null,
+            "period": 1_000_000,
+        }))
+        .unwrap();
+        let current = generator.token(&mut token).unwrap();
+        assert_eq!(current.code.len(), 6);
+        let fresh = generator
+            .clone()
+            .with_min_validity(Duration::from_secs(current.seconds_remaining + 1))
+            .token(&mut token)
+            .unwrap();
+        assert_eq!(fresh.code, current.next_code);
+        assert!(fresh.seconds_remaining > fresh.period);
+
+        let unreachable = generator
+            .clone()
+            .with_min_validity(Duration::from_secs(1_000_000))
+            .token(&mut token);
+        assert!(matches!(
+            unreachable,
+            Err(MambembeError::MinValidityTooLong {
+                min_validity: 1_000_000,
+                period: 1_000_000
+            })
+        ));
+
+        assert_eq!(
+            generator.verify(&mut token, &current.code, 1).unwrap(),
+            Some(0)
+        );
+        assert_eq!(
+            generator.verify(&mut token, &current.next_code, 1).unwrap(),
+            Some(1)
+        );
+        assert_eq!(
+            generator.verify(&mut token, &current.next_code, 0).unwrap(),
+            None
+        );
+
+        let app = AuthenticatorApp {
+            app_id: "".to_string(),
+            name: "".to_string(),
+            digits: 7,
+            secret_seed: "68656C6C6F".to_string().into(),
+        };
+        assert_eq!(generator.app_token(&app).unwrap().code.len(), 7);
+    }
+}
@@ -0,0 +1,143 @@
+use std::time::Duration;
+
+use reqwest::{header::HeaderValue, Method, StatusCode};
+
+/// Timeouts and retries applied to every request made to authy's api.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpPolicy {
+    pub connect_timeout: Duration,
+    pub request_timeout: Duration,
+    /// How many times a request is retried after a timeout, a connection
+    /// error, a 429 or a 5xx response. Non idempotent requests, like the
+    /// POSTs that send sms or approve a request, are only retried when the
+    /// connection failed, as authy never saw them.
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    /// Upper bound for the backoff, also applied to `Retry-After` so a
+    /// misbehaving server can't stall the client forever.
+    pub max_backoff: Duration,
+}
+
+impl Default for HttpPolicy {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(10),
+            request_timeout: Duration::from_secs(30),
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+impl HttpPolicy {
+    pub(crate) fn should_retry_status(
+        &self,
+        attempt: u32,
+        method: &Method,
+        status: StatusCode,
+    ) -> bool {
+        attempt < self.max_retries
+            && method.is_idempotent()
+            && (status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error())
+    }
+
+    pub(crate) fn should_retry_error(
+        &self,
+        attempt: u32,
+        method: &Method,
+        error: &reqwest::Error,
+    ) -> bool {
+        attempt < self.max_retries
+            && (error.is_connect() || (method.is_idempotent() && error.is_timeout()))
+    }
+
+    /// Exponential backoff for the given attempt, starting at 0.
+    pub(crate) fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt);
+        self.initial_backoff
+            .checked_mul(factor)
+            .map_or(self.max_backoff, |backoff| backoff.min(self.max_backoff))
+    }
+
+    pub(crate) fn retry_delay(&self, attempt: u32, retry_after: Option<&HeaderValue>) -> Duration {
+        retry_after.and_then(parse_retry_after).map_or_else(
+            || self.backoff(attempt),
+            |delay| delay.min(self.max_backoff),
+        )
+    }
+}
+
+/// Only the delay in seconds form is supported, dates fall back to the
+/// regular backoff.
+fn parse_retry_after(value: &HeaderValue) -> Option<Duration> {
+    value
+        .to_str()
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .map(Duration::from_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use reqwest::{header::HeaderValue, Method, StatusCode};
+
+    use crate::http_policy::HttpPolicy;
+
+    fn policy() -> HttpPolicy {
+        HttpPolicy {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn backoff_grows_exponentially_up_to_max() {
+        let policy = policy();
+        assert_eq!(policy.backoff(0), Duration::from_millis(100));
+        assert_eq!(policy.backoff(1), Duration::from_millis(200));
+        assert_eq!(policy.backoff(3), Duration::from_millis(800));
+        assert_eq!(policy.backoff(4), Duration::from_secs(1));
+        assert_eq!(policy.backoff(u32::MAX), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn retry_after_is_honored() {
+        let policy = policy();
+        let retry_after = HeaderValue::from_static("0");
+        assert_eq!(policy.retry_delay(2, Some(&retry_after)), Duration::ZERO);
+
+        let too_long = HeaderValue::from_static("3600");
+        assert_eq!(
+            policy.retry_delay(0, Some(&too_long)),
+            Duration::from_secs(1)
+        );
+
+        let date = HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT");
+        assert_eq!(
+            policy.retry_delay(1, Some(&date)),
+            Duration::from_millis(200)
+        );
+    }
+
+    #[test]
+    fn only_rate_limits_and_server_errors_are_retried() {
+        let policy = policy();
+        let get = Method::GET;
+        assert!(policy.should_retry_status(0, &get, StatusCode::TOO_MANY_REQUESTS));
+        assert!(policy.should_retry_status(0, &get, StatusCode::SERVICE_UNAVAILABLE));
+        assert!(!policy.should_retry_status(0, &get, StatusCode::BAD_REQUEST));
+        assert!(!policy.should_retry_status(policy.max_retries, &get, StatusCode::BAD_GATEWAY));
+    }
+
+    #[test]
+    fn non_idempotent_requests_are_not_retried_on_responses() {
+        let policy = policy();
+        assert!(!policy.should_retry_status(0, &Method::POST, StatusCode::TOO_MANY_REQUESTS));
+        assert!(!policy.should_retry_status(0, &Method::POST, StatusCode::SERVICE_UNAVAILABLE));
+        assert!(policy.should_retry_status(0, &Method::DELETE, StatusCode::SERVICE_UNAVAILABLE));
+    }
+}
@@ -1,14 +1,22 @@
 mod api_models;
+mod builder;
 pub mod client;
 mod constants;
 mod crypto;
 pub mod error;
+pub mod generator;
+pub mod http_policy;
 pub mod models;
 mod password;
+pub mod secret;
 mod tokens;
 mod utils;
 
 pub use crate::{
+    builder::AuthyClientBuilder,
     client::AuthyClient,
     error::{MambembeError, Result},
+    generator::TokenGenerator,
+    http_policy::HttpPolicy,
+    secret::Secret,
 };
@@ -1,13 +1,20 @@
-use data_encoding::{BASE32, HEXLOWER};
+use std::{collections::BTreeMap, convert::TryInto, fmt, str::FromStr, time::Duration};
+
+use data_encoding::{BASE32, HEXLOWER, HEXLOWER_PERMISSIVE};
 use serde::{Deserialize, Serialize};
 use sha2::Digest;
+use zeroize::Zeroizing;
 
 use crate::{
     client::TimeSync,
-    crypto::decrypt_data,
-    error::Result,
-    password::derive_key,
-    tokens::{calculate_future_tokens, decode_seed},
+    crypto::{decrypt_data, LEGACY_IV},
+    error::{InternalError, InternalResult, Result},
+    password::{derive_key, DEFAULT_ITERATIONS},
+    secret::Secret,
+    tokens::{
+        calculate_future_tokens, calculate_hotp_token, decode_seed, is_valid_base32,
+        OTHERS_DEFAULT_PERIOD,
+    },
     MambembeError,
 };
 
@@ -17,7 +24,44 @@ pub type RequestId = String;
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Device {
     pub id: u64,
-    secret_seed: String,
+    secret_seed: Secret<String>,
+}
+
+impl Device {
+    pub(crate) fn hash_secret(&self) -> String {
+        format!(
+            "{:x}",
+            sha2::Sha256::digest(self.secret_seed.expose().as_bytes())
+        )
+    }
+
+    pub(crate) fn calculate_tokens(
+        &self,
+        time_sync: Option<&TimeSync>,
+    ) -> Result<(String, String, String)> {
+        let seed = Zeroizing::new(
+            HEXLOWER
+                .decode(self.secret_seed.expose().as_bytes())
+                .map_err(|_| MambembeError::InvalidDeviceSeed)?,
+        );
+
+        Ok(calculate_future_tokens(&seed, time_sync))
+    }
+}
+
+/// A device registered on the authy account, as returned by the devices
+/// listing.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct DeviceInfo {
+    /// Listing id of the device, it can't be used to delete it.
+    pub id: String,
+    pub name: String,
+    pub app: String,
+    pub device_type: String,
+    pub city: String,
+    pub country: String,
+    pub created_at: String,
+    pub registered: bool,
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -26,9 +70,48 @@ pub enum CheckStatusResponse {
     RegisterAccount,
 }
 
+/// How authy should deliver verification codes.
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum VerificationMethod {
+    Push,
+    Sms,
+    Call,
+}
+
+impl FromStr for VerificationMethod {
+    type Err = MambembeError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "push" => Self::Push,
+            "sms" => Self::Sms,
+            "call" => Self::Call,
+            other => return Err(MambembeError::InvalidVerificationMethod(other.to_string())),
+        })
+    }
+}
+
+impl fmt::Display for VerificationMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Push => "push",
+            Self::Sms => "sms",
+            Self::Call => "call",
+        })
+    }
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum RegisterDeviceResponse {
-    RegistrationPending(RequestId),
+    /// With `push` the request has to be approved on another device, where
+    /// `approval_pin` is shown, otherwise the pin is sent by `provider` and
+    /// has to be given to `complete_registration`.
+    RegistrationPending {
+        request_id: RequestId,
+        approval_pin: Option<usize>,
+        provider: String,
+    },
 }
 
 #[derive(Debug, Deserialize, PartialEq, Eq)]
@@ -38,9 +121,38 @@ pub enum CheckRegistrationStatus {
     Accepted(Pin),
 }
 
-impl Device {
-    pub(crate) fn hash_secret(&self) -> String {
-        format!("{:x}", sha2::Sha256::digest(self.secret_seed.as_bytes()))
+/// A OneTouch request, e.g. a login, that can be approved or denied from any
+/// of the account's devices.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct ApprovalRequest {
+    pub uuid: String,
+    pub status: ApprovalStatus,
+    /// Who asked for the approval.
+    pub app_name: String,
+    pub message: String,
+    pub details: BTreeMap<String, String>,
+    /// Unix timestamps.
+    pub created_at: u64,
+    pub expires_at: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ApprovalStatus {
+    Pending,
+    Approved,
+    Denied,
+    Expired,
+}
+
+impl fmt::Display for ApprovalStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Pending => "pending",
+            Self::Approved => "approved",
+            Self::Denied => "denied",
+            Self::Expired => "expired",
+        })
     }
 }
 
@@ -53,45 +165,85 @@ pub struct AuthenticatorToken {
     original_name: Option<String>,
     password_timestamp: u64,
     salt: String,
-    unique_id: String,
-    derived_key: Option<Vec<u8>>,
+    pub unique_id: String,
+    /// Never saved, it is as good as the backup password.
+    #[serde(skip)]
+    derived_key: Option<Secret<Vec<u8>>>,
+    /// Only sent for tokens using the newer encryption format, together with
+    /// `unique_iv` (hex encoded).
+    #[serde(default)]
+    key_derivation_iterations: Option<u32>,
+    #[serde(default)]
+    unique_iv: Option<String>,
+    /// Authy doesn't send it, so SHA1 is assumed when missing.
+    #[serde(default)]
+    pub algorithm: OtpAlgorithm,
+    /// In seconds.
+    #[serde(default = "default_period")]
+    pub period: u64,
 }
 
-impl Device {
-    pub(crate) fn calculate_tokens(
-        &self,
-        time_sync: Option<&TimeSync>,
-    ) -> (String, String, String) {
-        let seed = HEXLOWER
-            .decode(self.secret_seed.as_bytes())
-            .expect("invalid secret");
-
-        calculate_future_tokens(&seed, time_sync)
-    }
+fn default_period() -> u64 {
+    OTHERS_DEFAULT_PERIOD
 }
 
 impl AuthenticatorToken {
+    pub fn apply_settings(&mut self, settings: &TokenSettings) {
+        if let Some(algorithm) = settings.algorithm {
+            self.algorithm = algorithm;
+        }
+        if let Some(period) = settings.period {
+            self.period = period;
+        }
+    }
+
     pub fn initialize_token(&mut self, password: &str) {
         if self.derived_key.is_some() {
             return;
         }
-        self.derived_key = Some(derive_key(password, &self.salt));
+        let iterations = self.key_derivation_iterations.unwrap_or(DEFAULT_ITERATIONS);
+        self.derived_key = Some(derive_key(password, &self.salt, iterations));
+    }
+
+    /// Drops the cached key and derives it again, needed when the backup
+    /// password changes.
+    pub fn reset_token(&mut self, password: &str) {
+        self.derived_key = None;
+        self.initialize_token(password);
     }
 
-    pub fn decrypt_seed(&self) -> Result<Vec<u8>> {
+    /// Steam Guard codes are letters instead of digits.
+    pub fn is_steam(&self) -> bool {
+        self.account_type.eq_ignore_ascii_case("steam")
+    }
+
+    pub fn decrypt_seed(&self) -> Result<Zeroizing<Vec<u8>>> {
         let derived_key = self
             .derived_key
             .as_ref()
+            .map(Secret::expose)
             .ok_or(MambembeError::AuthenticatorTokenNotInitialized)?;
 
-        let data = decrypt_data(derived_key, &self.encrypted_seed).map_err(|source| {
-            MambembeError::FailedToDecryptSeed {
+        let mut data = self
+            .iv()
+            .and_then(|iv| decrypt_data(derived_key, &iv, &self.encrypted_seed))
+            .map_err(|source| MambembeError::FailedToDecryptSeed {
                 service_name: self.name.clone(),
                 source,
-            }
-        })?;
+            })?;
 
-        Ok(data.to_ascii_uppercase())
+        data.make_ascii_uppercase();
+        Ok(data)
+    }
+
+    fn iv(&self) -> InternalResult<[u8; 16]> {
+        match &self.unique_iv {
+            Some(iv) => HEXLOWER_PERMISSIVE
+                .decode(iv.as_bytes())?
+                .try_into()
+                .map_err(|_| InternalError::InvalidIv),
+            None => Ok(LEGACY_IV),
+        }
     }
 
     pub fn dump_seed(&self) -> Result<String> {
@@ -101,24 +253,328 @@ impl AuthenticatorToken {
     }
 }
 
+/// Token settings authy doesn't sync, kept locally by the token's
+/// `unique_id` and applied on top of what was fetched, see
+/// [`AuthenticatorToken::apply_settings`].
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TokenSettings {
+    #[serde(default)]
+    pub algorithm: Option<OtpAlgorithm>,
+    /// In seconds.
+    #[serde(default)]
+    pub period: Option<u64>,
+}
+
+/// A counter based token imported by hand, each code is only valid once so
+/// `counter` has to be persisted after every generation.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HotpToken {
+    pub name: String,
+    /// Base32 encoded.
+    secret: Secret<String>,
+    pub digits: usize,
+    #[serde(default)]
+    pub algorithm: OtpAlgorithm,
+    /// Used by the next generated code.
+    pub counter: u64,
+}
+
+impl HotpToken {
+    /// `secret` is base32, spaces, padding and lowercase letters are
+    /// accepted. Codes have 6 to 8 digits.
+    pub fn new(
+        name: &str,
+        secret: &str,
+        digits: usize,
+        algorithm: OtpAlgorithm,
+        counter: u64,
+    ) -> Result<Self> {
+        let mut secret = Secret::new(
+            secret
+                .chars()
+                .filter(|c| !c.is_whitespace() && *c != '=')
+                .collect::<String>(),
+        );
+        secret.expose_mut().make_ascii_uppercase();
+        if secret.expose().is_empty() || !is_valid_base32(secret.expose()) {
+            return Err(MambembeError::InvalidSecret(name.to_string()));
+        }
+        if !(6..=8).contains(&digits) {
+            return Err(MambembeError::InvalidDigits(digits));
+        }
+        Ok(Self {
+            name: name.to_string(),
+            secret,
+            digits,
+            algorithm,
+            counter,
+        })
+    }
+
+    /// Returns the code for the current counter and moves to the next one,
+    /// persist the token before showing the code.
+    pub fn generate(&mut self) -> String {
+        let seed = decode_seed(self.secret.expose());
+        let code = calculate_hotp_token(&seed, self.digits, self.algorithm, self.counter);
+        self.counter += 1;
+        code
+    }
+
+    pub fn dump_seed(&self) -> &str {
+        self.secret.expose()
+    }
+}
+
+/// A generated code and the window in which it is valid.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct OtpCode {
+    pub code: String,
+    /// In seconds.
+    pub period: u64,
+    pub seconds_remaining: u64,
+    /// The time step used to generate `code`.
+    pub counter: u64,
+    /// Valid once `seconds_remaining` is over.
+    pub next_code: String,
+}
+
+impl OtpCode {
+    /// How long until a code valid for at least `min_validity` can be
+    /// generated, `None` when this one already is. Fails when no code of
+    /// this period would ever be.
+    pub fn wait_for(&self, min_validity: Duration) -> Result<Option<Duration>> {
+        if min_validity.as_secs() >= self.period {
+            return Err(MambembeError::MinValidityTooLong {
+                min_validity: min_validity.as_secs(),
+                period: self.period,
+            });
+        }
+        if self.seconds_remaining >= min_validity.as_secs() {
+            Ok(None)
+        } else {
+            Ok(Some(Duration::from_secs(self.seconds_remaining)))
+        }
+    }
+}
+
+/// Where the backup password is kept, only with `Inline` it is saved
+/// together with the client.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PasswordStorage {
+    #[default]
+    Inline,
+    /// In a keyring entry of its own.
+    Keyring,
+    /// Typed again on every session.
+    Prompt,
+}
+
+impl FromStr for PasswordStorage {
+    type Err = MambembeError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "inline" => Self::Inline,
+            "keyring" => Self::Keyring,
+            "prompt" => Self::Prompt,
+            other => return Err(MambembeError::InvalidPasswordStorage(other.to_string())),
+        })
+    }
+}
+
+/// Hash used to calculate a token.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum OtpAlgorithm {
+    #[default]
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl FromStr for OtpAlgorithm {
+    type Err = MambembeError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "sha1" => Self::Sha1,
+            "sha256" => Self::Sha256,
+            "sha512" => Self::Sha512,
+            _ => return Err(MambembeError::InvalidAlgorithm(s.to_string())),
+        })
+    }
+}
+
+/// Authy's own tokens (e.g. Twilio, Cloudflare or SendGrid), their seeds are
+/// hex encoded and not protected by the backup password.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AuthenticatorApp {
+    #[serde(rename = "_id")]
+    pub app_id: String,
+    pub name: String,
+    pub digits: usize,
+    pub(crate) secret_seed: Secret<String>,
+}
+
+impl AuthenticatorApp {
+    pub(crate) fn decode_seed(&self) -> Result<Zeroizing<Vec<u8>>> {
+        HEXLOWER_PERMISSIVE
+            .decode(self.secret_seed.expose().as_bytes())
+            .map(Zeroizing::new)
+            .map_err(|err| MambembeError::FailedToCalculateToken {
+                service_name: self.name.clone(),
+                source: err.into(),
+            })
+    }
+
+    pub fn dump_seed(&self) -> Result<String> {
+        self.decode_seed().map(|seed| BASE32.encode(&seed))
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::{models::AuthenticatorToken, password::derive_key};
+    use crate::{
+        models::{
+            AuthenticatorApp, AuthenticatorToken, Device, HotpToken, OtpAlgorithm, TokenSettings,
+        },
+        password::{derive_key, DEFAULT_ITERATIONS},
+        tokens::OTHERS_DEFAULT_PERIOD,
+        MambembeError,
+    };
 
-    #[test]
-    fn test_decrypt() {
-        let token = AuthenticatorToken {
+    fn token(encrypted_seed: &str, salt: &str) -> AuthenticatorToken {
+        AuthenticatorToken {
             account_type: "".to_string(),
             digits: 6,
-            encrypted_seed: "Y8yn1UMAmLjmCOEOi8FJc6G5zvBzZxaFEPpPgl+QaxE=".to_string(),
+            encrypted_seed: encrypted_seed.to_string(),
             name: "".to_string(),
             original_name: None,
             password_timestamp: 0,
-            salt: "".to_string(),
+            salt: salt.to_string(),
             unique_id: "".to_string(),
-            derived_key: Some(derive_key("123456", "salty")),
-        };
+            derived_key: None,
+            key_derivation_iterations: None,
+            unique_iv: None,
+            algorithm: OtpAlgorithm::Sha1,
+            period: OTHERS_DEFAULT_PERIOD,
+        }
+    }
+
+    #[test]
+    fn test_decrypt() {
+        let mut token = token("Y8yn1UMAmLjmCOEOi8FJc6G5zvBzZxaFEPpPgl+QaxE=", "");
+        token.derived_key = Some(derive_key("123456", "salty", DEFAULT_ITERATIONS));
+        let decrypted = token.decrypt_seed().unwrap();
+        assert_eq!(String::from_utf8_lossy(&decrypted), "MY SECRET SEED01");
+    }
+
+    #[test]
+    fn test_decrypt_with_unique_iv() {
+        let mut token = token("I6XQwfXfQe40MkRBTYgPnBN69GqEgiE4y2hjTPKY8iE=", "salty");
+        token.key_derivation_iterations = Some(10000);
+        token.unique_iv = Some("8f1e2d3c4b5a69788796a5b4c3d2e1f0".to_string());
+        token.initialize_token("123456");
         let decrypted = token.decrypt_seed().unwrap();
         assert_eq!(String::from_utf8_lossy(&decrypted), "MY SECRET SEED01");
+
+        token.unique_iv = Some("8f1e".to_string());
+        assert!(token.decrypt_seed().is_err());
+    }
+
+    #[test]
+    fn hotp_token_moves_to_next_counter() {
+        // "12345678901234567890" from RFC 4226
+        let mut token = HotpToken::new(
+            "hotp",
+            "gezd gnbv gy3t qojq gezd gnbv gy3t qojq====",
+            6,
+            OtpAlgorithm::Sha1,
+            0,
+        )
+        .unwrap();
+        assert_eq!(token.generate(), "755224");
+        assert_eq!(token.generate(), "287082");
+        assert_eq!(token.counter, 2);
+        assert_eq!(token.dump_seed(), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+
+        assert!(HotpToken::new("hotp", "not base32!", 6, OtpAlgorithm::Sha1, 0).is_err());
+    }
+
+    #[test]
+    fn hotp_token_digits_are_bounded() {
+        for digits in [0, 5, 9, 100] {
+            assert!(matches!(
+                HotpToken::new("hotp", "JBSWY3DPEHPK3PXP", digits, OtpAlgorithm::Sha1, 0),
+                Err(MambembeError::InvalidDigits(d)) if d == digits
+            ));
+        }
+        for digits in 6..=8 {
+            let mut token =
+                HotpToken::new("hotp", "JBSWY3DPEHPK3PXP", digits, OtpAlgorithm::Sha1, 0).unwrap();
+            assert_eq!(token.generate().len(), digits);
+        }
+    }
+
+    #[test]
+    fn derived_key_is_not_saved() {
+        let mut saved = serde_json::to_value(token("", "salty")).unwrap();
+        saved["derived_key"] = serde_json::json!([1, 2, 3]);
+        let mut token: AuthenticatorToken = serde_json::from_value(saved).unwrap();
+        assert!(token.derived_key.is_none());
+
+        token.initialize_token("123456");
+        let persisted = serde_json::to_value(&token).unwrap();
+        assert!(persisted.get("derived_key").is_none());
+    }
+
+    #[test]
+    fn settings_override_fetched_values() {
+        let mut token = token("", "salty");
+        token.apply_settings(&TokenSettings::default());
+        assert_eq!(token.algorithm, OtpAlgorithm::Sha1);
+        assert_eq!(token.period, 30);
+
+        token.apply_settings(&TokenSettings {
+            algorithm: Some(OtpAlgorithm::Sha256),
+            period: Some(60),
+        });
+        assert_eq!(token.algorithm, OtpAlgorithm::Sha256);
+        assert_eq!(token.period, 60);
+    }
+
+    #[test]
+    fn test_dump_app_seed() {
+        let app = AuthenticatorApp {
+            app_id: "".to_string(),
+            name: "".to_string(),
+            digits: 7,
+            secret_seed: "68656C6C6F".to_string().into(),
+        };
+        assert_eq!(app.dump_seed().unwrap(), "NBSWY3DP");
+    }
+
+    #[test]
+    fn secrets_are_redacted_from_debug() {
+        let app = AuthenticatorApp {
+            app_id: "".to_string(),
+            name: "".to_string(),
+            digits: 7,
+            secret_seed: "68656C6C6F".to_string().into(),
+        };
+        assert!(!format!("{:?}", app).contains("68656C6C6F"));
+
+        let hotp = HotpToken::new("GitHub", "JBSWY3DPEHPK3PXP", 6, OtpAlgorithm::Sha1, 0).unwrap();
+        assert!(!format!("{:?}", hotp).contains("JBSWY3DPEHPK3PXP"));
+
+        let device: Device =
+            serde_json::from_value(serde_json::json!({"id": 1, "secret_seed": "device-seed"}))
+                .unwrap();
+        assert!(!format!("{:?}", device).contains("device-seed"));
+
+        let mut token = token("", "salty");
+        token.initialize_token("123456");
+        assert!(format!("{:?}", token).contains("derived_key: Some([REDACTED])"));
     }
 }
@@ -1,18 +1,24 @@
-use std::time::SystemTime;
+use std::{iter, time::SystemTime};
 
-use data_encoding::{Encoding, Specification};
+use data_encoding::{Encoding, Specification, HEXLOWER_PERMISSIVE};
+use hmac::{Hmac, Mac};
 use itertools::Itertools;
 use lazy_static::lazy_static;
-use slauth::oath::{
-    hotp::{HOTPBuilder, HOTPContext},
-    HashesAlgorithm,
-};
+use sha1::Sha1;
+use slauth::oath::{hotp::HOTPBuilder, HashesAlgorithm};
+use zeroize::Zeroizing;
 
-use crate::{client::TimeSync, error::InternalResult};
+use crate::{
+    client::TimeSync,
+    error::{InternalError, InternalResult},
+    models::{OtpAlgorithm, OtpCode},
+};
 
 const DEFAULT_OTP_DIGITS: usize = 7;
-const AUTHY_DEFAULT_PERIOD: u64 = 10;
-const OTHERS_DEFAULT_PERIOD: u64 = 30;
+pub(crate) const AUTHY_DEFAULT_PERIOD: u64 = 10;
+pub(crate) const OTHERS_DEFAULT_PERIOD: u64 = 30;
+const STEAM_DIGITS: usize = 5;
+const STEAM_ALPHABET: &[u8] = b"23456789BCDFGHJKMNPQRTVWXY";
 
 lazy_static! {
     static ref BASE32_NOPAD: Encoding = {
@@ -24,39 +30,196 @@ lazy_static! {
     };
 }
 
-#[tracing::instrument]
+impl From<OtpAlgorithm> for HashesAlgorithm {
+    fn from(algorithm: OtpAlgorithm) -> Self {
+        match algorithm {
+            OtpAlgorithm::Sha1 => HashesAlgorithm::SHA1,
+            OtpAlgorithm::Sha256 => HashesAlgorithm::SHA256,
+            OtpAlgorithm::Sha512 => HashesAlgorithm::SHA512,
+        }
+    }
+}
+
+#[tracing::instrument(skip(seed))]
 pub(crate) fn calculate_token(
     seed: &[u8],
     digits: usize,
-    time_sync: Option<&TimeSync>,
-) -> InternalResult<String> {
+    algorithm: OtpAlgorithm,
+    period: u64,
+    time: u64,
+) -> InternalResult<OtpCode> {
     let seed = decode_seed(seed);
-    // let seed = HEXLOWER.encode(&seed);
-    let time = get_time(time_sync);
-    let s = build_slauth_context(&seed, digits, time / OTHERS_DEFAULT_PERIOD);
-    Ok(s.gen())
+    otp_code_at(period, time, |counter| {
+        generate_code(&seed, digits, algorithm.into(), counter)
+    })
+}
+
+/// Authy's own apps always use the same period as the device tokens.
+#[tracing::instrument(skip(seed))]
+pub(crate) fn calculate_app_token(
+    seed: &[u8],
+    digits: usize,
+    time: u64,
+) -> InternalResult<OtpCode> {
+    otp_code_at(AUTHY_DEFAULT_PERIOD, time, |counter| {
+        generate_code(seed, digits, HashesAlgorithm::SHA1, counter)
+    })
+}
+
+/// Steam Guard codes are regular 30 seconds SHA1 codes written with
+/// `STEAM_ALPHABET` instead of digits.
+#[tracing::instrument(skip(seed))]
+pub(crate) fn calculate_steam_token(seed: &[u8], time: u64) -> InternalResult<OtpCode> {
+    let seed = decode_seed(seed);
+    otp_code_at(OTHERS_DEFAULT_PERIOD, time, |counter| {
+        steam_code(&seed, counter)
+    })
+}
+
+fn steam_code(seed: &[u8], counter: u64) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(seed).expect("hmac accepts keys of any size");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0xf) as usize;
+    let mut value = u32::from_be_bytes([
+        hash[offset],
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]) & 0x7fff_ffff;
+    (0..STEAM_DIGITS)
+        .map(|_| {
+            let symbol = STEAM_ALPHABET[value as usize % STEAM_ALPHABET.len()];
+            value /= STEAM_ALPHABET.len() as u32;
+            symbol as char
+        })
+        .collect()
+}
+
+fn otp_code_at<F>(period: u64, time: u64, generate: F) -> InternalResult<OtpCode>
+where
+    F: Fn(u64) -> String,
+{
+    let counter = time
+        .checked_div(period)
+        .ok_or(InternalError::InvalidPeriod)?;
+    Ok(OtpCode {
+        code: generate(counter),
+        next_code: generate(counter + 1),
+        period,
+        seconds_remaining: period - time % period,
+        counter,
+    })
+}
+
+/// Looks for `code` in the current step and in up to `window` steps around
+/// it, closest ones first, returning the step relative to the current one.
+#[tracing::instrument(skip(seed, code))]
+pub(crate) fn verify_token(
+    seed: &[u8],
+    digits: usize,
+    algorithm: OtpAlgorithm,
+    period: u64,
+    time: u64,
+    code: &str,
+    window: u32,
+) -> InternalResult<Option<i64>> {
+    let seed = decode_seed(seed);
+    find_step(period, time, code, window, |counter| {
+        generate_code(&seed, digits, algorithm.into(), counter)
+    })
+}
+
+#[tracing::instrument(skip(seed, code))]
+pub(crate) fn verify_steam_token(
+    seed: &[u8],
+    time: u64,
+    code: &str,
+    window: u32,
+) -> InternalResult<Option<i64>> {
+    let seed = decode_seed(seed);
+    let code = code.to_ascii_uppercase();
+    find_step(OTHERS_DEFAULT_PERIOD, time, &code, window, |counter| {
+        steam_code(&seed, counter)
+    })
+}
+
+fn find_step<F>(
+    period: u64,
+    time: u64,
+    code: &str,
+    window: u32,
+    generate: F,
+) -> InternalResult<Option<i64>>
+where
+    F: Fn(u64) -> String,
+{
+    let counter = time
+        .checked_div(period)
+        .ok_or(InternalError::InvalidPeriod)?;
+    let mut steps = iter::once(0).chain((1..=i64::from(window)).flat_map(|step| [-step, step]));
+    Ok(steps.find(|step| {
+        counter
+            .checked_add_signed(*step)
+            .is_some_and(|counter| generate(counter) == code)
+    }))
+}
+
+/// Counter based tokens, the caller is responsible for never reusing a
+/// counter.
+#[tracing::instrument(skip(seed))]
+pub(crate) fn calculate_hotp_token(
+    seed: &[u8],
+    digits: usize,
+    algorithm: OtpAlgorithm,
+    counter: u64,
+) -> String {
+    generate_code(seed, digits, algorithm.into(), counter)
 }
 
-pub(crate) fn decode_seed<T>(seed: T) -> Vec<u8>
+pub(crate) fn is_valid_base32(seed: &str) -> bool {
+    BASE32_NOPAD.decode(seed.as_bytes()).is_ok()
+}
+
+/// Decrypted seeds are either base32 or hex, anything else means the
+/// wrong key was used.
+pub(crate) fn is_valid_seed(seed: &[u8]) -> bool {
+    !seed.is_empty()
+        && (BASE32_NOPAD.decode(seed).is_ok() || HEXLOWER_PERMISSIVE.decode(seed).is_ok())
+}
+
+pub(crate) fn decode_seed<T>(seed: T) -> Zeroizing<Vec<u8>>
 where
     T: AsRef<[u8]>,
 {
-    BASE32_NOPAD
-        .decode(seed.as_ref())
-        .unwrap_or_else(|_| seed.as_ref().to_vec())
+    Zeroizing::new(
+        BASE32_NOPAD
+            .decode(seed.as_ref())
+            .unwrap_or_else(|_| seed.as_ref().to_vec()),
+    )
 }
 
-#[tracing::instrument]
-pub(crate) fn build_slauth_context(seed: &[u8], digits: usize, padded_time: u64) -> HOTPContext {
-    HOTPBuilder::new()
-        .algorithm(HashesAlgorithm::SHA1)
+/// slauth always pads codes to 6 digits, so they are padded again to the
+/// requested amount, e.g. `07081804` would be `7081804` otherwise.
+// slauth's algorithm doesn't implement Debug
+#[tracing::instrument(skip(seed, algorithm))]
+pub(crate) fn generate_code(
+    seed: &[u8],
+    digits: usize,
+    algorithm: HashesAlgorithm,
+    padded_time: u64,
+) -> String {
+    let code = HOTPBuilder::new()
+        .algorithm(algorithm)
         .secret(seed)
         .counter(padded_time)
         .digits(digits)
         .build()
+        .gen();
+    format!("{:0>width$}", code.trim_start_matches('0'), width = digits)
 }
 
-#[tracing::instrument]
+#[tracing::instrument(skip(seed))]
 pub(crate) fn calculate_future_tokens(
     seed: &[u8],
     time_sync: Option<&TimeSync>,
@@ -65,8 +228,9 @@ pub(crate) fn calculate_future_tokens(
     (0..3)
         .map(|i| timestamp + AUTHY_DEFAULT_PERIOD * i)
         .map(|t| t / AUTHY_DEFAULT_PERIOD)
-        .map(|padded_time| build_slauth_context(seed, DEFAULT_OTP_DIGITS, padded_time))
-        .map(|ctx| ctx.gen())
+        .map(|padded_time| {
+            generate_code(seed, DEFAULT_OTP_DIGITS, HashesAlgorithm::SHA1, padded_time)
+        })
         .collect_tuple()
         .expect("should not happen")
 }
@@ -85,11 +249,30 @@ pub(crate) fn get_time(time_sync: Option<&TimeSync>) -> u64 {
 
 #[cfg(test)]
 mod tests {
-    use crate::tokens::calculate_token;
+    use crate::{
+        models::OtpAlgorithm,
+        tokens::{
+            calculate_app_token, calculate_hotp_token, calculate_steam_token, calculate_token,
+            get_time, is_valid_seed, verify_steam_token, verify_token,
+        },
+    };
+
+    #[test]
+    fn only_base32_or_hex_seeds_are_valid() {
+        assert!(is_valid_seed(b"JBSWY3DPEHPK3PXP"));
+        assert!(is_valid_seed(b"1bcc2b0a43e94a90916a04079190af40"));
+        assert!(!is_valid_seed(b""));
+        assert!(!is_valid_seed(b"\x8f\x01garbage"));
+    }
 
     #[test]
     fn calculate_token_works_with_unpaded_seed() {
-        assert!(!calculate_token(b"NBSXS", 6, None).unwrap().is_empty())
+        assert!(
+            !calculate_token(b"NBSXS", 6, OtpAlgorithm::Sha1, 30, get_time(None))
+                .unwrap()
+                .code
+                .is_empty()
+        )
     }
 
     #[test]
@@ -97,9 +280,147 @@ mod tests {
         assert!(!calculate_token(
             b"DZQS7RJ3CX7FP4RFZNHMGOH64UIJBSDTLO67TRCGMWG6GDS2IPKT",
             6,
-            None
+            OtpAlgorithm::Sha1,
+            30,
+            get_time(None)
         )
         .unwrap()
+        .code
         .is_empty())
     }
+
+    /// Test vectors from RFC 6238 appendix B.
+    #[test]
+    fn rfc_6238_test_vectors() {
+        let sha1_seed = b"12345678901234567890";
+        let sha256_seed = b"12345678901234567890123456789012";
+        let sha512_seed = b"1234567890123456789012345678901234567890123456789012345678901234";
+        let vectors: &[(&[u8], OtpAlgorithm, u64, &str)] = &[
+            (sha1_seed, OtpAlgorithm::Sha1, 59, "94287082"),
+            (sha256_seed, OtpAlgorithm::Sha256, 59, "46119246"),
+            (sha512_seed, OtpAlgorithm::Sha512, 59, "90693936"),
+            (sha1_seed, OtpAlgorithm::Sha1, 1111111109, "07081804"),
+            (sha256_seed, OtpAlgorithm::Sha256, 1111111109, "68084774"),
+            (sha512_seed, OtpAlgorithm::Sha512, 1111111109, "25091201"),
+            (sha1_seed, OtpAlgorithm::Sha1, 20000000000, "65353130"),
+            (sha256_seed, OtpAlgorithm::Sha256, 20000000000, "77737706"),
+            (sha512_seed, OtpAlgorithm::Sha512, 20000000000, "47863826"),
+        ];
+        for (seed, algorithm, time, expected) in vectors {
+            assert_eq!(
+                calculate_token(seed, 8, *algorithm, 30, *time)
+                    .unwrap()
+                    .code,
+                *expected,
+                "{:?} at {}",
+                algorithm,
+                time
+            );
+        }
+    }
+
+    #[test]
+    fn period_is_honored() {
+        let seed = b"12345678901234567890";
+        let token = |time| {
+            calculate_token(seed, 6, OtpAlgorithm::Sha256, 60, time)
+                .unwrap()
+                .code
+        };
+        assert_eq!(token(60), token(119));
+        assert_ne!(token(119), token(120));
+        assert!(calculate_token(seed, 6, OtpAlgorithm::Sha1, 0, 59).is_err());
+    }
+
+    /// Test vectors from RFC 4226 appendix D.
+    #[test]
+    fn rfc_4226_test_vectors() {
+        let expected = [
+            "755224", "287082", "359152", "969429", "338314", "254676", "287922", "162583",
+            "399871", "520489",
+        ];
+        for (counter, expected) in expected.iter().enumerate() {
+            assert_eq!(
+                calculate_hotp_token(
+                    b"12345678901234567890",
+                    6,
+                    OtpAlgorithm::Sha1,
+                    counter as u64
+                ),
+                *expected
+            );
+        }
+    }
+
+    /// Calculated with a python implementation of Steam Guard.
+    #[test]
+    fn steam_known_answers() {
+        let seed = b"12345678901234567890";
+        let vectors = [
+            (59, "PV9M4"),
+            (1111111109, "PY4YB"),
+            (1234567890, "VHHQY"),
+            (2000000000, "9N776"),
+        ];
+        for (time, expected) in vectors.iter() {
+            assert_eq!(calculate_steam_token(seed, *time).unwrap().code, *expected);
+        }
+        assert_eq!(calculate_steam_token(seed, 59).unwrap().period, 30);
+        assert_eq!(verify_steam_token(seed, 89, "pv9m4", 1).unwrap(), Some(-1));
+    }
+
+    #[test]
+    fn verify_token_within_window() {
+        let seed = b"12345678901234567890";
+        let verify = |time, code, window| {
+            verify_token(seed, 8, OtpAlgorithm::Sha1, 30, time, code, window).unwrap()
+        };
+        assert_eq!(verify(59, "94287082", 0), Some(0));
+        assert_eq!(verify(89, "94287082", 1), Some(-1));
+        assert_eq!(verify(29, "94287082", 1), Some(1));
+        assert_eq!(verify(89, "94287082", 0), None);
+        assert_eq!(verify(149, "94287082", 2), None);
+        assert_eq!(verify(59, "00000000", 1), None);
+        // Codes starting with a zero are typed with it
+        assert_eq!(verify(1111111109, "07081804", 0), Some(0));
+        assert_eq!(verify(1111111139, "07081804", 1), Some(-1));
+        assert_eq!(verify(1111111109, "7081804", 0), None);
+    }
+
+    #[test]
+    fn validity_window_is_reported() {
+        let seed = b"12345678901234567890";
+        let code = calculate_token(seed, 8, OtpAlgorithm::Sha1, 30, 59).unwrap();
+        assert_eq!(code.counter, 1);
+        assert_eq!(code.period, 30);
+        assert_eq!(code.seconds_remaining, 1);
+        let next = calculate_token(seed, 8, OtpAlgorithm::Sha1, 30, 60).unwrap();
+        assert_eq!(code.next_code, next.code);
+        assert_eq!(next.seconds_remaining, 30);
+    }
+
+    #[test]
+    fn codes_keep_leading_zeroes() {
+        let seed = b"12345678901234567890";
+        // 07081804 with 8 digits, as in the RFC 6238 vectors
+        assert_eq!(
+            calculate_hotp_token(seed, 7, OtpAlgorithm::Sha1, 37037036),
+            "7081804"
+        );
+        assert_eq!(
+            calculate_hotp_token(seed, 8, OtpAlgorithm::Sha1, 37037036),
+            "07081804"
+        );
+        for counter in 0..200 {
+            let code = calculate_app_token(seed, 7, counter * 10).unwrap().code;
+            assert_eq!(code.len(), 7);
+        }
+    }
+
+    #[test]
+    fn app_token_has_requested_digits() {
+        let code = calculate_app_token(b"hello", 7, get_time(None)).unwrap();
+        assert_eq!(code.code.len(), 7);
+        assert_eq!(code.period, 10);
+    }
 }
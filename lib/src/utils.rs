@@ -1,22 +1,28 @@
 use data_encoding::BASE64;
 use reqwest::{
     header::{HeaderMap, HeaderValue},
-    Client,
+    Certificate, Client, NoProxy, Proxy,
 };
 use rsa::{pkcs1::DecodeRsaPrivateKey, RsaPrivateKey};
+use serde::de::DeserializeOwned;
 use tracing::debug;
+use zeroize::Zeroizing;
 
-use crate::api_models::AuthyApiError;
 pub use crate::error::{MambembeError, Result};
+use crate::{api_models::AuthyApiError, builder::HttpOptions};
 
 const DAMAGED_TOKEN_ERROR: &str = "60043";
 
-pub(crate) fn client_builder() -> Client {
+pub(crate) fn client_builder(options: &HttpOptions) -> Result<Client> {
+    let user_agent = options
+        .user_agent
+        .clone()
+        .unwrap_or_else(|| format!("Mambembe v{}", env!("CARGO_PKG_VERSION")));
     let mut headers = HeaderMap::new();
     headers.insert(
         "X-User-Agent",
-        HeaderValue::from_str(&format!("Mambembe v{}", env!("CARGO_PKG_VERSION")))
-            .expect("invalid caracters on header value"),
+        HeaderValue::from_str(&user_agent)
+            .map_err(|_| MambembeError::InvalidUserAgent(user_agent.clone()))?,
     );
     headers.insert(
         "User-Agent",
@@ -27,11 +33,30 @@ pub(crate) fn client_builder() -> Client {
         .expect("invalid characters on header value"),
     );
 
-    Client::builder()
+    let mut builder = Client::builder()
         .gzip(true)
         .default_headers(headers)
+        .connect_timeout(options.policy.connect_timeout)
+        .timeout(options.policy.request_timeout);
+
+    if let Some(proxy) = &options.proxy {
+        let proxy = Proxy::all(proxy)
+            .map_err(MambembeError::HttpClientConfiguration)?
+            .no_proxy(NoProxy::from_env());
+        builder = builder.proxy(proxy);
+    }
+
+    for pem in &options.root_certificates {
+        let certificates =
+            Certificate::from_pem_bundle(pem).map_err(MambembeError::HttpClientConfiguration)?;
+        for certificate in certificates {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+
+    builder
         .build()
-        .unwrap()
+        .map_err(MambembeError::HttpClientConfiguration)
 }
 
 pub(crate) async fn check_api_errors(response: reqwest::Response) -> Result<reqwest::Response> {
@@ -60,9 +85,23 @@ pub(crate) async fn check_api_errors(response: reqwest::Response) -> Result<reqw
     }
 }
 
+pub(crate) async fn parse_response<T>(response: reqwest::Response) -> Result<T>
+where
+    T: DeserializeOwned,
+{
+    response
+        .json()
+        .await
+        .map_err(MambembeError::UnexpectedResponse)
+}
+
 pub(crate) fn parse_private_key(key: &str) -> Result<RsaPrivateKey> {
-    let key: String = key.lines().filter(|l| !l.starts_with('-')).collect();
-    let decoded = BASE64.decode(key.as_bytes()).unwrap();
+    let key: Zeroizing<String> =
+        Zeroizing::new(key.lines().filter(|l| !l.starts_with('-')).collect());
+    let decoded = BASE64
+        .decode(key.as_bytes())
+        .map(Zeroizing::new)
+        .map_err(|_| MambembeError::MissingField("private_key"))?;
 
     let private_key = RsaPrivateKey::from_pkcs1_der(&decoded)?;
     Ok(private_key)
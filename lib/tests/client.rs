@@ -5,8 +5,11 @@ mod docker {
     use lazy_static::lazy_static;
     use mambembe_lib::{
         client::AuthyClientApi,
-        models::{CheckRegistrationStatus, CheckStatusResponse, RegisterDeviceResponse},
-        AuthyClient,
+        models::{
+            ApprovalStatus, CheckRegistrationStatus, CheckStatusResponse, RegisterDeviceResponse,
+            VerificationMethod,
+        },
+        AuthyClient, HttpPolicy, MambembeError,
     };
     use mambembe_stub_server::start_wiremock;
     use serde_json::{json, Value};
@@ -26,11 +29,19 @@ mod docker {
     }
 
     fn get_test_client(wiremock_url: &str) -> AuthyClient {
+        get_test_client_with(wiremock_url, json!({}))
+    }
+
+    fn get_test_client_with(wiremock_url: &str, overrides: Value) -> AuthyClient {
         let mut client_config = CLIENT_CONFIG.clone();
-        client_config.as_object_mut().unwrap().insert(
+        let config = client_config.as_object_mut().unwrap();
+        config.insert(
             "url".to_string(),
             Value::String(format!("{}/json", wiremock_url)),
         );
+        for (key, value) in overrides.as_object().unwrap() {
+            config.insert(key.clone(), value.clone());
+        }
 
         serde_json::from_value(client_config).unwrap()
     }
@@ -40,11 +51,68 @@ mod docker {
         let url = start_wiremock().await.unwrap();
         let client = get_test_client(&url);
         let tokens = client.list_authenticator_tokens().await.unwrap();
-        assert_eq!(tokens.len(), 2);
+        assert_eq!(tokens.len(), 4);
         let lastpass = &tokens[0];
         assert_eq!(lastpass.name, "LastPass");
     }
 
+    #[tokio::test]
+    async fn verify_backup_password() {
+        let url = start_wiremock().await.unwrap();
+        let mut client = get_test_client(&url);
+        client.verify_backup_password().await.unwrap();
+
+        client.set_backup_password("wrong");
+        let result = client.verify_backup_password().await;
+        assert!(matches!(result, Err(MambembeError::InvalidBackupPassword)));
+    }
+
+    #[tokio::test]
+    async fn change_backup_password() {
+        let url = start_wiremock().await.unwrap();
+        let mut client = get_test_client_with(&url, json!({"backup_password": "old"}));
+
+        let result = client.change_backup_password("wrong").await;
+        assert!(matches!(result, Err(MambembeError::InvalidBackupPassword)));
+        let result = client.verify_backup_password().await;
+        assert!(matches!(result, Err(MambembeError::InvalidBackupPassword)));
+
+        let tokens = client.change_backup_password("abc").await.unwrap();
+        assert_eq!(tokens[2].dump_seed().unwrap(), "JBSWY3DPEHPK3PXP");
+        client.verify_backup_password().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn decrypt_legacy_and_new_seed_formats() {
+        let url = start_wiremock().await.unwrap();
+        let client = get_test_client(&url);
+        let mut tokens = client.list_authenticator_tokens().await.unwrap();
+        let github = &mut tokens[2];
+        github.initialize_token("abc");
+        assert_eq!(github.dump_seed().unwrap(), "JBSWY3DPEHPK3PXP");
+        let gitlab = &mut tokens[3];
+        gitlab.initialize_token("abc");
+        assert_eq!(
+            gitlab.dump_seed().unwrap(),
+            "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ"
+        );
+    }
+
+    #[tokio::test]
+    async fn list_authenticator_apps() {
+        let url = start_wiremock().await.unwrap();
+        let client = get_test_client(&url);
+        let apps = client.list_authenticator_apps().await.unwrap();
+        assert_eq!(apps.len(), 1);
+        let twilio = &apps[0];
+        assert_eq!(twilio.name, "Twilio");
+        assert_eq!(twilio.digits, 7);
+        let token = client.get_app_otp_token(twilio).await.unwrap();
+        assert_eq!(token.code.len(), 7);
+        assert_eq!(token.period, 10);
+        assert!(token.seconds_remaining <= 10);
+    }
+
     #[tokio::test]
     async fn check_current_device() {
         let url = start_wiremock().await.unwrap();
@@ -59,6 +127,57 @@ mod docker {
         client.check_current_device().await.unwrap();
     }
 
+    #[tokio::test]
+    async fn list_devices() {
+        let url = start_wiremock().await.unwrap();
+        let client = get_test_client(&url);
+        let devices = client.list_devices().await.unwrap();
+        assert_eq!(devices.len(), 2);
+        let phone = &devices[0];
+        assert_eq!(phone.name, "Pixel 4a");
+        assert_eq!(phone.device_type, "android");
+        assert_eq!(phone.city, "Berlin");
+        assert!(phone.registered);
+        assert!(!devices[1].registered);
+    }
+
+    #[tokio::test]
+    async fn unregister_device() {
+        let url = start_wiremock().await.unwrap();
+        let mut client = get_test_client(&url);
+        client.unregister_device().await.unwrap();
+        assert!(matches!(
+            client.check_current_device().await,
+            Err(MambembeError::DeviceNotInitialized)
+        ));
+    }
+
+    #[tokio::test]
+    async fn delete_device_takes_the_numeric_device_id() {
+        let url = start_wiremock().await.unwrap();
+        let client = get_test_client(&url);
+        client.delete_device(12334).await.unwrap();
+
+        let listing_id = &client.list_devices().await.unwrap()[0].id;
+        assert!(listing_id.parse::<u64>().is_err());
+        let response = reqwest::Client::new()
+            .post(&format!(
+                "{}/json/users/1234/devices/{}/delete",
+                url, listing_id
+            ))
+            .form(&[
+                ("api_key", "test"),
+                ("device_id", "12334"),
+                ("otp1", "1"),
+                ("otp2", "2"),
+                ("otp3", "3"),
+            ])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 404);
+    }
+
     #[tokio::test]
     async fn register_flow() {
         let url = start_wiremock().await.unwrap();
@@ -69,8 +188,17 @@ mod docker {
             CheckStatusResponse::RegisterDevice,
             client.check_user_status("123456").await.unwrap()
         );
-        let response = client.register_device().await.unwrap();
-        let RegisterDeviceResponse::RegistrationPending(request_id) = response;
+        let response = client
+            .register_device(VerificationMethod::Push)
+            .await
+            .unwrap();
+        let RegisterDeviceResponse::RegistrationPending {
+            request_id,
+            approval_pin,
+            provider,
+        } = response;
+        assert_eq!(approval_pin, Some(1));
+        assert_eq!(provider, "push");
 
         let response = client.check_registration(&request_id).await.unwrap();
         assert_eq!(CheckRegistrationStatus::Pending, response);
@@ -83,4 +211,189 @@ mod docker {
         };
         client.complete_registration(&pin).await.unwrap();
     }
+
+    #[tokio::test]
+    async fn register_flow_via_sms() {
+        let url = start_wiremock().await.unwrap();
+
+        let mut client =
+            AuthyClient::with_url(&format!("{}/json", url), "test-device", "1234").unwrap();
+        client.check_user_status("123456").await.unwrap();
+        let RegisterDeviceResponse::RegistrationPending {
+            approval_pin,
+            provider,
+            ..
+        } = client
+            .register_device(VerificationMethod::Sms)
+            .await
+            .unwrap();
+        assert_eq!(approval_pin, None);
+        assert_eq!(provider, "sms");
+        client.complete_registration("123456").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn register_account_flow() {
+        let url = start_wiremock().await.unwrap();
+
+        let mut client =
+            AuthyClient::with_url(&format!("{}/json", url), "test-device", "1234").unwrap();
+        assert_eq!(
+            CheckStatusResponse::RegisterAccount,
+            client.check_user_status("1-5550000").await.unwrap()
+        );
+        client
+            .create_account("new@example.com", "1", "5550000", VerificationMethod::Sms)
+            .await
+            .unwrap();
+        client.complete_registration("123456").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn transport_error() {
+        let client = get_test_client("http://127.0.0.1:1");
+        assert!(matches!(
+            client.check_current_device().await,
+            Err(MambembeError::TransportError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn unexpected_response() {
+        let url = start_wiremock().await.unwrap();
+        let client = get_test_client_with(&url, json!({"authy_id": 666}));
+        assert!(matches!(
+            client.list_devices().await,
+            Err(MambembeError::UnexpectedResponse(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn unknown_status_message() {
+        let url = start_wiremock().await.unwrap();
+        let mut client =
+            AuthyClient::with_url(&format!("{}/json", url), "test-device", "1234").unwrap();
+        match client.check_user_status("1-5550001").await {
+            Err(MambembeError::UnknownStatusMessage(message)) => assert_eq!(message, "suspended"),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn registration_accepted_without_pin() {
+        let url = start_wiremock().await.unwrap();
+        let client = get_test_client(&url);
+        assert!(matches!(
+            client.check_registration("nopin").await,
+            Err(MambembeError::MissingField("pin"))
+        ));
+    }
+
+    #[tokio::test]
+    async fn private_key_missing() {
+        let url = start_wiremock().await.unwrap();
+        let mut client = get_test_client_with(
+            &url,
+            json!({"device": {"id": 666, "secret_seed": "1bcc2b0a43e94a90916a04079190af40"}}),
+        );
+        assert!(matches!(
+            client.fetch_private_keys().await,
+            Err(MambembeError::MissingField("private_key"))
+        ));
+    }
+
+    #[tokio::test]
+    async fn fetch_private_keys_and_sign() {
+        let url = start_wiremock().await.unwrap();
+        let mut client = get_test_client(&url);
+        client.fetch_private_keys().await.unwrap();
+        let signature = client.sign_with_device_key(b"message").unwrap();
+        assert_eq!(signature.len(), 128);
+    }
+
+    #[tokio::test]
+    async fn sync_time_with_server() {
+        let url = start_wiremock().await.unwrap();
+        let mut client = get_test_client(&url);
+        let max_age = Duration::from_secs(60);
+        assert!(client.sync_time_if_older_than(max_age).await.unwrap());
+        // The fixture's clock is stuck in the past
+        let offset = client.time_sync().unwrap().offset();
+        assert!(offset < 0);
+        assert!(!client.sync_time_if_older_than(max_age).await.unwrap());
+
+        // Syncing again measures the same offset instead of stacking on it
+        client.sync_time_with_server().await.unwrap();
+        assert!((client.time_sync().unwrap().offset() - offset).abs() <= 1);
+        client.list_authenticator_tokens().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn list_approval_requests() {
+        let url = start_wiremock().await.unwrap();
+        let client = get_test_client(&url);
+        let requests = client.list_approval_requests().await.unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].app_name, "Acme VPN");
+        assert_eq!(requests[0].details["Username"], "oncall");
+        assert_eq!(requests[1].expires_at, None);
+        assert!(requests[1].details.is_empty());
+
+        let request = client
+            .get_approval_request(&requests[0].uuid)
+            .await
+            .unwrap();
+        assert_eq!(request, requests[0]);
+        assert_eq!(request.status, ApprovalStatus::Pending);
+    }
+
+    #[tokio::test]
+    async fn slow_responses_time_out() {
+        let url = start_wiremock().await.unwrap();
+        let mut client = AuthyClient::with_url(&format!("{}/json", url), "test-device", "1234")
+            .unwrap()
+            .with_http_policy(HttpPolicy {
+                request_timeout: Duration::from_millis(200),
+                max_retries: 0,
+                ..Default::default()
+            })
+            .unwrap();
+        match client.check_user_status("1-5550002").await {
+            Err(MambembeError::TransportError(err)) => assert!(err.is_timeout()),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn failing_responses_are_retried() {
+        let url = start_wiremock().await.unwrap();
+        let mut client = AuthyClient::with_url(&format!("{}/json", url), "test-device", "1234")
+            .unwrap()
+            .with_http_policy(HttpPolicy {
+                max_retries: 2,
+                initial_backoff: Duration::from_millis(10),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(
+            CheckStatusResponse::RegisterDevice,
+            client.check_user_status("1-5550003").await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn offline_mode_never_contacts_authy() {
+        let url = start_wiremock().await.unwrap();
+        let online = get_test_client(&url);
+        let apps = online.list_authenticator_apps().await.unwrap();
+
+        let mut client = get_test_client(&url);
+        client.set_offline(true);
+        let err = client.list_authenticator_apps().await.unwrap_err();
+        assert!(matches!(err, MambembeError::OfflineMode));
+
+        let generator = client.token_generator();
+        let token = generator.app_token(&apps[0]).unwrap();
+        assert_eq!(token.code.len(), apps[0].digits);
+    }
 }